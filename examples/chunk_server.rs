@@ -0,0 +1,239 @@
+//! Reference server for the chunked upload protocol, for local testing of
+//! `--chunk-threshold`. Sessions are kept in memory; parts are appended to a file in the
+//! data directory and finalized files are stored as `<data-dir>/<uuid>`.
+//!
+//! ```text
+//! cargo run --example chunk_server -- --listen 127.0.0.1:8080 --data-dir ./chunk-data
+//! smb-watch --alc-api-url http://127.0.0.1:8080 --chunk-threshold 1M ...
+//! ```
+//!
+//! `POST /api/auth/google` accepts any ID token and bearer tokens are not checked. Uploads
+//! with an `Idempotency-Key` that has already been finalized are answered with 409.
+//! `GET /api/files/{uuid}` reports a stored file's size and SHA-256 for `--verify`, and
+//! `DELETE /api/files/{uuid}` removes it.
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[derive(Parser)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Directory for partial and finalized uploads
+    #[arg(long, default_value = "chunk-data")]
+    data_dir: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct CreateSession {
+    filename: String,
+    size: u64,
+    sha256: String,
+    chunk_size: u64,
+}
+
+struct Session {
+    filename: String,
+    size: u64,
+    sha256: String,
+    chunk_size: u64,
+    received_parts: u64,
+    partial: PathBuf,
+    idempotency_key: Option<String>,
+}
+
+struct AppState {
+    data_dir: PathBuf,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Idempotency keys of finalized uploads and the uuid they were stored under.
+    stored: Mutex<HashMap<String, uuid::Uuid>>,
+}
+
+type Shared = State<Arc<AppState>>;
+type Reply = (StatusCode, Json<Value>);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    std::fs::create_dir_all(args.data_dir.join("partial"))?;
+    let state = Arc::new(AppState {
+        data_dir: args.data_dir,
+        sessions: Mutex::new(HashMap::new()),
+        stored: Mutex::new(HashMap::new()),
+    });
+
+    let app = Router::new()
+        .route("/api/auth/google", post(login))
+        .route("/api/files/by-key/{key}", get(by_key))
+        .route("/api/files/{uuid}", get(file_info).delete(delete_file))
+        .route("/api/files/uploads", post(create))
+        .route("/api/files/uploads/{id}", get(status).delete(abort))
+        .route("/api/files/uploads/{id}/parts/{n}", put(part))
+        .route("/api/files/uploads/{id}/complete", post(complete))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&args.listen).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+fn reply(status: StatusCode, body: Value) -> Reply {
+    (status, Json(body))
+}
+
+fn error(status: StatusCode, message: &str) -> Reply {
+    reply(status, json!({ "error": message }))
+}
+
+async fn login() -> Reply {
+    reply(
+        StatusCode::OK,
+        json!({
+            "access_token": "local-test-token",
+            "expires_in": 3600,
+            "user": { "tenant_id": uuid::Uuid::nil() },
+        }),
+    )
+}
+
+async fn by_key(State(state): Shared, Path(key): Path<String>) -> Reply {
+    match state.stored.lock().unwrap().get(&key) {
+        Some(uuid) => reply(StatusCode::OK, json!({ "uuid": uuid })),
+        None => error(StatusCode::NOT_FOUND, "not stored"),
+    }
+}
+
+async fn file_info(State(state): Shared, Path(uuid): Path<uuid::Uuid>) -> Reply {
+    match std::fs::read(state.data_dir.join(uuid.to_string())) {
+        Ok(content) => reply(
+            StatusCode::OK,
+            json!({
+                "uuid": uuid,
+                "size": content.len(),
+                "sha256": hex::encode(Sha256::digest(&content)),
+            }),
+        ),
+        Err(_) => error(StatusCode::NOT_FOUND, "no such file"),
+    }
+}
+
+async fn delete_file(State(state): Shared, Path(uuid): Path<uuid::Uuid>) -> StatusCode {
+    state.stored.lock().unwrap().retain(|_, stored| *stored != uuid);
+    match std::fs::remove_file(state.data_dir.join(uuid.to_string())) {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn create(State(state): Shared, headers: HeaderMap, Json(req): Json<CreateSession>) -> Reply {
+    let idempotency_key = headers
+        .get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    if let Some(uuid) = idempotency_key.as_ref().and_then(|k| state.stored.lock().unwrap().get(k).copied()) {
+        return reply(StatusCode::CONFLICT, json!({ "uuid": uuid }));
+    }
+    if req.chunk_size == 0 {
+        return error(StatusCode::BAD_REQUEST, "chunk_size must be greater than zero");
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    let partial = state.data_dir.join("partial").join(&id);
+    if let Err(e) = std::fs::File::create(&partial) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    println!("{}: started {} ({} bytes)", id, req.filename, req.size);
+    state.sessions.lock().unwrap().insert(
+        id.clone(),
+        Session {
+            filename: req.filename,
+            size: req.size,
+            sha256: req.sha256,
+            chunk_size: req.chunk_size,
+            received_parts: 0,
+            partial,
+            idempotency_key,
+        },
+    );
+    reply(StatusCode::CREATED, json!({ "upload_id": id }))
+}
+
+async fn status(State(state): Shared, Path(id): Path<String>) -> Reply {
+    match state.sessions.lock().unwrap().get(&id) {
+        Some(session) => reply(StatusCode::OK, json!({ "received_parts": session.received_parts })),
+        None => error(StatusCode::NOT_FOUND, "no such upload"),
+    }
+}
+
+async fn part(State(state): Shared, Path((id, n)): Path<(String, u64)>, body: Bytes) -> Reply {
+    let mut sessions = state.sessions.lock().unwrap();
+    let Some(session) = sessions.get_mut(&id) else {
+        return error(StatusCode::NOT_FOUND, "no such upload");
+    };
+    // A repeated part whose acknowledgement was lost
+    if n < session.received_parts {
+        return reply(StatusCode::OK, json!({ "received_parts": session.received_parts }));
+    }
+    if n > session.received_parts {
+        return error(StatusCode::CONFLICT, "parts must be sent in order");
+    }
+    let expected = session.chunk_size.min(session.size.saturating_sub(n * session.chunk_size));
+    if body.len() as u64 != expected {
+        return error(StatusCode::BAD_REQUEST, "unexpected part length");
+    }
+    let written = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&session.partial)
+        .and_then(|mut f| f.write_all(&body));
+    if let Err(e) = written {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    session.received_parts += 1;
+    reply(StatusCode::OK, json!({ "received_parts": session.received_parts }))
+}
+
+async fn complete(State(state): Shared, Path(id): Path<String>) -> Reply {
+    let Some(session) = state.sessions.lock().unwrap().remove(&id) else {
+        return error(StatusCode::NOT_FOUND, "no such upload");
+    };
+    let content = match std::fs::read(&session.partial) {
+        Ok(content) => content,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let _ = std::fs::remove_file(&session.partial);
+    if content.len() as u64 != session.size || hex::encode(Sha256::digest(&content)) != session.sha256 {
+        return error(StatusCode::UNPROCESSABLE_ENTITY, "size or SHA-256 does not match");
+    }
+    let uuid = uuid::Uuid::new_v4();
+    if let Err(e) = std::fs::write(state.data_dir.join(uuid.to_string()), &content) {
+        return error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+    }
+    if let Some(key) = session.idempotency_key {
+        state.stored.lock().unwrap().insert(key, uuid);
+    }
+    println!("{}: stored {} as {}", id, session.filename, uuid);
+    reply(StatusCode::CREATED, json!({ "uuid": uuid }))
+}
+
+async fn abort(State(state): Shared, Path(id): Path<String>) -> StatusCode {
+    match state.sessions.lock().unwrap().remove(&id) {
+        Some(session) => {
+            let _ = std::fs::remove_file(&session.partial);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
@@ -1,5 +1,183 @@
-use chrono::{DateTime, Utc};
-use clap::Parser;
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, Utc};
+use std::time::{Duration, SystemTime};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+/// A point in time given either as an age relative to now or as an absolute timestamp.
+#[derive(Clone, Copy, Debug)]
+pub enum TimeLimit {
+    Ago(Duration),
+    At(DateTime<Utc>),
+}
+
+impl TimeLimit {
+    pub fn resolve(&self, now: SystemTime) -> SystemTime {
+        match self {
+            TimeLimit::Ago(d) => now.checked_sub(*d).unwrap_or(SystemTime::UNIX_EPOCH),
+            TimeLimit::At(dt) => SystemTime::from(*dt),
+        }
+    }
+}
+
+/// A time-of-day range in which uploads may run, optionally limited to some weekdays and
+/// with its own bandwidth limit.
+#[derive(Clone, Debug)]
+pub struct UploadWindow {
+    /// Weekdays the window starts on, Monday first.
+    pub days: [bool; 7],
+    /// Minutes since local midnight. A window that ends at or before its start runs past
+    /// midnight into the next day.
+    pub start: u32,
+    pub end: u32,
+    /// Bytes per second; `--max-bandwidth` applies when not given.
+    pub rate: Option<u64>,
+}
+
+impl UploadWindow {
+    pub fn contains(&self, time: NaiveDateTime) -> bool {
+        let day = time.weekday().num_days_from_monday() as usize;
+        let minute = time.hour() * 60 + time.minute();
+        if self.start < self.end {
+            self.days[day] && (self.start..self.end).contains(&minute)
+        } else {
+            (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+        }
+    }
+}
+
+/// One step of a `--transform` pipeline.
+#[derive(Clone, Debug)]
+pub enum TransformStep {
+    /// External command. `{input}` and `{output}` in its arguments are replaced by file paths;
+    /// without them the file is passed on stdin and the output read from stdout.
+    Exec(Vec<String>),
+    /// Gzip-compress the file and append `.gz` to its name.
+    Gzip,
+    /// Change the extension of the name the file is uploaded under.
+    Ext(String),
+}
+
+impl std::fmt::Display for TransformStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransformStep::Exec(args) => write!(f, "exec:{}", args[0]),
+            TransformStep::Gzip => write!(f, "gzip"),
+            TransformStep::Ext(ext) => write!(f, "ext:{}", ext),
+        }
+    }
+}
+
+/// A `--transform` entry: one step for the files matching a pattern.
+#[derive(Clone, Debug)]
+pub struct TransformRule {
+    pub pattern: String,
+    pub step: TransformStep,
+}
+
+/// Where the reference time for the next scan comes from.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatermarkSource {
+    /// Highest file mtime seen during the scan (the share's own clock)
+    Observed,
+    /// Mtime of a probe file written to the share at scan start (needs write access)
+    Probe,
+    /// This machine's clock at scan start
+    Local,
+}
+
+/// How the scanner treats symbolic links and junctions.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// Ignore links entirely
+    Skip,
+    /// Follow links to files and directories, skipping links that would loop
+    Follow,
+}
+
+/// How the scanner treats dot-files and files with the hidden or system attribute.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HiddenPolicy {
+    /// Treat hidden files like any other file
+    Include,
+    /// Skip hidden files and do not descend into hidden directories
+    Ignore,
+}
+
+/// What to do with the server copy when a previously uploaded file disappears from the share.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteAction {
+    /// Only log the deletion; the server copy is kept
+    Log,
+    /// Delete the server copy
+    Delete,
+    /// Archive the server copy
+    Archive,
+}
+
+/// Where changed files are delivered.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SinkKind {
+    /// rust-alc-api's /api/files endpoint (Google sign-in)
+    AlcApi,
+    /// A mirror of the share in a local or network directory
+    LocalDir,
+    /// An S3-compatible bucket (AWS S3, MinIO, ...)
+    S3,
+    /// A WebDAV collection
+    Webdav,
+}
+
+impl SinkKind {
+    /// Name used on the command line and to key per-destination state.
+    pub fn name(self) -> &'static str {
+        match self {
+            SinkKind::AlcApi => "alc-api",
+            SinkKind::LocalDir => "local-dir",
+            SinkKind::S3 => "s3",
+            SinkKind::Webdav => "webdav",
+        }
+    }
+}
+
+/// How file content is sent to the server.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadMode {
+    /// Base64 content in a JSON body (the whole file is held in memory)
+    Json,
+    /// Streamed multipart/form-data with a `file` part
+    Multipart,
+    /// Streamed raw body, with the file name and type in headers
+    Raw,
+}
+
+/// Optional metadata sent with each upload, so it can be left out for servers that reject it.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataField {
+    /// Path relative to the scan root
+    RelativePath,
+    /// File size in bytes
+    Size,
+    /// Last modification time
+    Mtime,
+    /// Creation time
+    Ctime,
+    /// SHA-256 of the content
+    Sha256,
+    /// Host and share (or local path) the file was read from
+    Source,
+    /// smb-watch version
+    Version,
+}
+
+/// What to do with a file whose content does not match the type its name claims.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Upload it with the type detected from the content
+    Allow,
+    /// Like allow, but log a warning
+    Warn,
+    /// Do not upload it; it counts as a failed upload
+    Reject,
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "smb-watch", about = "Monitor SMB share and upload changed files via HTTP")]
@@ -32,6 +210,50 @@ pub struct Config {
     #[arg(long, env = "ALC_API_URL", default_value = "https://rust-alc-api-566bls5vfq-an.a.run.app")]
     pub alc_api_url: String,
 
+    /// Destinations for changed files (repeatable or comma-separated); each file is delivered to all of them
+    #[arg(long, value_enum, value_name = "SINK", value_delimiter = ',', default_value = "alc-api")]
+    pub sink: Vec<SinkKind>,
+
+    /// local-dir sink: directory the share is mirrored into
+    #[arg(long, value_name = "PATH")]
+    pub mirror_dir: Option<std::path::PathBuf>,
+
+    /// s3 sink: endpoint URL, e.g. http://127.0.0.1:9000 for MinIO (path-style addressing)
+    #[arg(long, env = "S3_ENDPOINT", value_name = "URL")]
+    pub s3_endpoint: Option<String>,
+
+    /// s3 sink: bucket name
+    #[arg(long, env = "S3_BUCKET", value_name = "BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    /// s3 sink: region used for request signing
+    #[arg(long, env = "AWS_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// s3 sink: key prefix for uploaded objects (e.g. `shaken/`)
+    #[arg(long, value_name = "PREFIX", default_value = "")]
+    pub s3_prefix: String,
+
+    /// s3 sink: access key ID
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    pub s3_access_key: Option<String>,
+
+    /// s3 sink: secret access key
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_key: Option<String>,
+
+    /// webdav sink: URL of the collection files are uploaded into
+    #[arg(long, env = "WEBDAV_URL", value_name = "URL")]
+    pub webdav_url: Option<String>,
+
+    /// webdav sink: username for basic authentication
+    #[arg(long, env = "WEBDAV_USER")]
+    pub webdav_user: Option<String>,
+
+    /// webdav sink: password for basic authentication
+    #[arg(long, env = "WEBDAV_PASS", hide_env_values = true)]
+    pub webdav_pass: Option<String>,
+
     /// Path to state file storing last run timestamp
     #[arg(long, default_value = "last_run.txt")]
     pub state_file: std::path::PathBuf,
@@ -48,8 +270,18 @@ pub struct Config {
     #[arg(long, default_value = "info")]
     pub log_level: String,
 
+    /// How the reference time for the next scan is determined. The share's clock may differ
+    /// from this machine's, and file mtimes are stamped by the share
+    #[arg(long, value_enum, value_name = "SOURCE", default_value = "observed")]
+    pub watermark: WatermarkSource,
+
+    /// Re-check files modified up to this many minutes before the last run's watermark
+    #[arg(long, value_name = "MINUTES", default_value_t = 5)]
+    pub watermark_overlap_mins: u64,
+
     /// スキャン基準時刻を上書き。RFC3339形式 (例: 2026-02-10T00:00:00Z)。
-    /// 指定すると last_run.txt より優先される。
+    /// 指定すると last_run.txt より優先される。これより新しいファイルは
+    /// マニフェストと一致していても再ハッシュして内容を確認する。
     #[arg(long, value_name = "DATETIME", value_parser = parse_since)]
     pub since: Option<DateTime<Utc>>,
 
@@ -73,6 +305,241 @@ pub struct Config {
     /// Local directory path to monitor (enables local mode, skips SMB mount)
     #[arg(long, value_name = "PATH")]
     pub local_path: Option<std::path::PathBuf>,
+
+    /// Only upload files matching this glob (repeatable). Matched against the path relative
+    /// to the scan root; `**` crosses directories and patterns without `/` match at any depth
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip files and directories matching this glob (repeatable, same syntax as --include).
+    /// Excluded directories are not descended into
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Disable the built-in excludes (Thumbs.db, desktop.ini, ~$ lock files, *.tmp, ...)
+    #[arg(long, default_value_t = false)]
+    pub no_default_excludes: bool,
+
+    /// Number of threads listing directories and hashing files concurrently during a scan
+    #[arg(long, value_name = "N", default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+    pub scan_workers: u16,
+
+    /// Reuse cached listings of directories whose mtime has not changed since the last scan.
+    /// Files modified in place do not change their directory's mtime and are only picked up
+    /// by the periodic full rescan
+    #[arg(long, default_value_t = false)]
+    pub incremental: bool,
+
+    /// Incremental mode: hours between full rescans that list every directory
+    #[arg(long, value_name = "HOURS", default_value_t = 24)]
+    pub full_rescan_hours: u64,
+
+    /// Whether symbolic links and junctions are skipped or followed
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "skip")]
+    pub links: LinkPolicy,
+
+    /// Whether dot-files and files with the hidden or system attribute are scanned
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "include")]
+    pub hidden: HiddenPolicy,
+
+    /// Do not descend more than this many directory levels (1 = only files directly in the root)
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Skip files smaller than this size (bytes, or with a K/M/G suffix)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Skip files larger than this size (bytes, or with a K/M/G suffix)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Skip empty files
+    #[arg(long, default_value_t = false)]
+    pub skip_empty: bool,
+
+    /// Only consider files modified before this age (e.g. 30d, 12h, 15m) or RFC3339 time
+    #[arg(long, value_name = "AGE|DATETIME", value_parser = parse_time_limit)]
+    pub older_than: Option<TimeLimit>,
+
+    /// Only consider files modified after this age (e.g. 30d, 12h, 15m) or RFC3339 time
+    #[arg(long, value_name = "AGE|DATETIME", value_parser = parse_time_limit)]
+    pub newer_than: Option<TimeLimit>,
+
+    /// Defer files written to within this many seconds, so half-written scans are not uploaded
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub quiet_period_secs: u64,
+
+    /// Action for the server copy when an uploaded file is deleted from the share.
+    /// Renamed files (same content) are always sent as a rename instead
+    #[arg(long, value_enum, value_name = "ACTION", default_value = "log")]
+    pub on_delete: DeleteAction,
+
+    /// Metadata fields not to send with uploads (repeatable or comma-separated)
+    #[arg(long, value_enum, value_name = "FIELD", value_delimiter = ',')]
+    pub omit_metadata: Vec<MetadataField>,
+
+    /// MIME type for files with the given extension, taking precedence over detection
+    /// (repeatable or comma-separated), e.g. `pdf1=application/pdf`
+    #[arg(long, value_name = "EXT=TYPE", value_delimiter = ',', value_parser = parse_mime_mapping)]
+    pub mime_map: Vec<(String, String)>,
+
+    /// What to do when a file's content does not match the type its extension (or --mime-map) claims
+    #[arg(long, value_enum, value_name = "POLICY", default_value = "warn")]
+    pub mime_mismatch: MismatchPolicy,
+
+    /// Step to run on files matching a glob before uploading them, as PATTERN=STEP, where STEP
+    /// is `exec:COMMAND ARGS...`, `gzip` or `ext:EXT` (repeatable). Steps given for the same
+    /// pattern run in order; the first pattern that matches a file selects its steps
+    #[arg(long, value_name = "PATTERN=STEP", value_parser = parse_transform)]
+    pub transform: Vec<TransformRule>,
+
+    /// Time limit for each external transform command
+    #[arg(long, value_name = "SECS", default_value_t = 600)]
+    pub transform_timeout_secs: u64,
+
+    /// Ask the server whether it already holds each file (by idempotency key) before uploading
+    #[arg(long, default_value_t = false)]
+    pub preflight_check: bool,
+
+    /// After each upload, read the stored copy's size and SHA-256 back from the destination and
+    /// compare them with the local file. A mismatch removes the copy and counts as a failed upload
+    #[arg(long, default_value_t = false)]
+    pub verify: bool,
+
+    /// Number of files uploaded in parallel
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+
+    /// Attempts per file within a run, including the first. Only network errors, timeouts,
+    /// 408, 429 and 5xx responses are retried; other failures go to the failed list at once
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_attempts: u32,
+
+    /// Upper bound on the total wait between attempts for one file
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    pub max_retry_delay_secs: u64,
+
+    /// Move a file to the dead-letter list after its upload has failed in this many runs.
+    /// Dead-lettered files are reported every run but not retried until requeued
+    #[arg(long, value_name = "RUNS", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub dead_letter_after: u32,
+
+    /// Move a file from the dead-letter list back to the retry list (repeatable; path as
+    /// shown in the dead-letter report)
+    #[arg(long, value_name = "PATH")]
+    pub requeue: Vec<std::path::PathBuf>,
+
+    /// Move every file in the dead-letter list back to the retry list
+    #[arg(long, default_value_t = false)]
+    pub requeue_all: bool,
+
+    /// How file content is sent. `multipart` and `raw` stream the file in chunks, so memory
+    /// use does not grow with file size; `json` is for servers that only accept base64 JSON
+    #[arg(long, value_enum, value_name = "MODE", default_value = "json")]
+    pub upload_mode: UploadMode,
+
+    /// Upload files of at least this size (bytes, or with a K/M/G suffix) in parts over a
+    /// resumable session instead of a single request
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub chunk_threshold: Option<u64>,
+
+    /// Part size for chunked uploads (bytes, or with a K/M/G suffix)
+    #[arg(long, value_name = "SIZE", default_value = "8M", value_parser = parse_chunk_size)]
+    pub chunk_size: u64,
+
+    /// Limit total upload throughput to this many bytes per second (K/M/G suffix), shared by
+    /// all parallel uploads
+    #[arg(long, value_name = "RATE", value_parser = parse_bandwidth)]
+    pub max_bandwidth: Option<u64>,
+
+    /// Only start uploads within this local time window (repeatable), e.g. `18:00-07:00` or
+    /// `sat,sun 00:00-24:00` or `mon-fri 12:00-13:00=256K` with its own bandwidth limit.
+    /// Files found outside the windows are queued until the next one
+    #[arg(long, value_name = "[DAYS ]HH:MM-HH:MM[=RATE]", value_parser = parse_upload_window)]
+    pub upload_window: Vec<UploadWindow>,
+
+    /// Proxy for all HTTP traffic (http://, https://, socks5:// or socks5h:// URL). Without it,
+    /// HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY from the environment apply
+    #[arg(long, env = "SMB_WATCH_PROXY", value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Username for the proxy (basic authentication)
+    #[arg(long, env = "SMB_WATCH_PROXY_USER")]
+    pub proxy_user: Option<String>,
+
+    /// Password for the proxy
+    #[arg(long, env = "SMB_WATCH_PROXY_PASS", hide_env_values = true)]
+    pub proxy_pass: Option<String>,
+
+    /// Hosts that bypass --proxy: comma-separated domains, IP addresses or CIDR ranges
+    #[arg(long, env = "NO_PROXY", value_name = "HOSTS")]
+    pub no_proxy: Option<String>,
+
+    /// Also trust the root CA certificates in this PEM file (repeatable), e.g. for a TLS
+    /// inspection proxy
+    #[arg(long, value_name = "PEM")]
+    pub ca_cert: Vec<std::path::PathBuf>,
+
+    /// Client certificate chain for mutual TLS (PEM). The private key may be in the same file
+    #[arg(long, value_name = "PEM")]
+    pub client_cert: Option<std::path::PathBuf>,
+
+    /// Private key for --client-cert (PEM), if it is in a separate file
+    #[arg(long, value_name = "PEM", requires = "client_cert")]
+    pub client_key: Option<std::path::PathBuf>,
+
+    /// Seconds to wait for a connection, including proxy and TLS handshakes
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub connect_timeout_secs: u64,
+
+    /// Seconds to wait for each read from the server before a request fails
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub read_timeout_secs: u64,
+
+    /// Upper bound on a whole request, including sending the file. Unlimited by default, as
+    /// large or bandwidth-limited uploads may take long
+    #[arg(long, value_name = "SECS")]
+    pub timeout_secs: Option<u64>,
+
+    /// Keep running and upload changes as they appear instead of exiting after one scan
+    #[arg(long, default_value_t = false)]
+    pub watch: bool,
+
+    /// Watch mode: seconds without filesystem events before a scan is triggered
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    pub debounce_secs: u64,
+
+    /// Watch mode: seconds between periodic scans (the only trigger for SMB paths)
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    pub poll_interval_secs: u64,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Compare the share with the files rust-alc-api holds for the tenant and report files
+    /// missing on the server, only on the server, or with different content. Options for the
+    /// share, state and uploads go before the command name
+    Reconcile(ReconcileArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ReconcileArgs {
+    /// Upload files that are not on the server, regardless of the watermark
+    #[arg(long, default_value_t = false)]
+    pub upload_missing: bool,
+
+    /// Also upload files whose content differs from every copy the server holds under their path
+    #[arg(long, default_value_t = false)]
+    pub upload_differing: bool,
+
+    /// Where to write the report (tab-separated). Defaults to reconcile_report.tsv alongside
+    /// --state-file
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<std::path::PathBuf>,
 }
 
 fn parse_since(s: &str) -> std::result::Result<DateTime<Utc>, String> {
@@ -80,3 +547,292 @@ fn parse_since(s: &str) -> std::result::Result<DateTime<Utc>, String> {
         .map(|dt| dt.with_timezone(&Utc))
         .map_err(|e| format!("Invalid RFC3339 datetime '{}': {}", s, e))
 }
+
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_uppercase() {
+                'K' => 1u64 << 10,
+                'M' => 1 << 20,
+                'G' => 1 << 30,
+                'T' => 1 << 40,
+                _ => return Err(format!("Invalid size suffix in '{}' (use K, M, G or T)", s)),
+            };
+            (&s[..i], multiplier)
+        }
+        _ => (s, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid size '{}'", s))
+}
+
+fn parse_chunk_size(s: &str) -> std::result::Result<u64, String> {
+    match parse_size(s)? {
+        0 => Err("Chunk size must be greater than zero".to_string()),
+        n => Ok(n),
+    }
+}
+
+fn parse_mime_mapping(s: &str) -> std::result::Result<(String, String), String> {
+    let (ext, mime) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid MIME mapping '{}' (expected EXT=TYPE)", s))?;
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() || ext.contains(['/', '\\']) {
+        return Err(format!("Invalid extension in MIME mapping '{}'", s));
+    }
+    let mime = mime.trim();
+    mime.parse::<mime_guess::mime::Mime>()
+        .map_err(|e| format!("Invalid MIME type '{}': {}", mime, e))?;
+    Ok((ext, mime.to_string()))
+}
+
+fn parse_transform(s: &str) -> std::result::Result<TransformRule, String> {
+    let (pattern, step) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid transform '{}' (expected PATTERN=STEP)", s))?;
+    let pattern = pattern.trim();
+    if pattern.is_empty() {
+        return Err(format!("Missing pattern in transform '{}'", s));
+    }
+    let step = step.trim();
+    let step = match step.split_once(':') {
+        Some(("exec", command)) => {
+            let args = split_command(command)?;
+            if args.is_empty() {
+                return Err(format!("Missing command in transform '{}'", s));
+            }
+            TransformStep::Exec(args)
+        }
+        Some(("ext", ext)) => {
+            let ext = ext.trim().trim_start_matches('.');
+            if ext.is_empty() || ext.contains(['/', '\\']) {
+                return Err(format!("Invalid extension in transform '{}'", s));
+            }
+            TransformStep::Ext(ext.to_string())
+        }
+        None if step == "gzip" => TransformStep::Gzip,
+        _ => return Err(format!("Unknown transform step '{}' (use exec:COMMAND, gzip or ext:EXT)", step)),
+    };
+    Ok(TransformRule {
+        pattern: pattern.to_string(),
+        step,
+    })
+}
+
+/// Split a command line at whitespace. Double quotes group words containing spaces;
+/// backslashes are literal, as in Windows paths.
+fn split_command(s: &str) -> std::result::Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in s.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("Unbalanced quotes in command '{}'", s.trim()));
+    }
+    args.extend(current);
+    Ok(args)
+}
+
+fn parse_bandwidth(s: &str) -> std::result::Result<u64, String> {
+    match parse_size(s)? {
+        0 => Err("Bandwidth must be greater than zero".to_string()),
+        n => Ok(n),
+    }
+}
+
+fn parse_upload_window(s: &str) -> std::result::Result<UploadWindow, String> {
+    let (spec, rate) = match s.split_once('=') {
+        Some((spec, rate)) => (spec.trim(), Some(parse_bandwidth(rate)?)),
+        None => (s.trim(), None),
+    };
+    let (days, times) = match spec.rsplit_once(char::is_whitespace) {
+        Some((days, times)) => (parse_weekdays(days)?, times),
+        None => ([true; 7], spec),
+    };
+    let (start, end) = times
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid upload window '{}' (expected e.g. 'mon-fri 08:00-18:00')", s))?;
+    Ok(UploadWindow {
+        days,
+        start: parse_time_of_day(start)?,
+        end: parse_time_of_day(end)?,
+        rate,
+    })
+}
+
+/// Weekday names or ranges separated by commas, e.g. `mon-fri` or `sat,sun`.
+fn parse_weekdays(s: &str) -> std::result::Result<[bool; 7], String> {
+    const NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+    let day = |name: &str| {
+        let name = name.trim().to_ascii_lowercase();
+        NAMES
+            .iter()
+            .position(|d| name.starts_with(d))
+            .ok_or_else(|| format!("Invalid weekday '{}' (use mon, tue, ... sun)", name))
+    };
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (mut d, last) = (day(first)?, day(last)?);
+                days[d] = true;
+                while d != last {
+                    d = (d + 1) % 7;
+                    days[d] = true;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// `HH:MM` as minutes since midnight; `24:00` is accepted as the end of the day.
+fn parse_time_of_day(s: &str) -> std::result::Result<u32, String> {
+    let s = s.trim();
+    s.split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)))
+        .filter(|&(h, m)| m < 60 && (h < 24 || (h, m) == (24, 0)))
+        .map(|(h, m)| h * 60 + m)
+        .ok_or_else(|| format!("Invalid time of day '{}' (expected HH:MM)", s))
+}
+
+fn parse_time_limit(s: &str) -> std::result::Result<TimeLimit, String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(TimeLimit::At(dt.with_timezone(&Utc)));
+    }
+    let s = s.trim();
+    let unit_secs = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        Some('w') => 7 * 86400,
+        _ => return Err(format!("Invalid age or RFC3339 datetime '{}' (e.g. 30d, 12h, 15m)", s)),
+    };
+    s[..s.len() - 1]
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit_secs))
+        .map(|secs| TimeLimit::Ago(Duration::from_secs(secs)))
+        .ok_or_else(|| format!("Invalid age '{}' (e.g. 30d, 12h, 15m)", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    /// A time in the week of Monday 2026-10-12; `day` 0 is that Monday, 7 the next one.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        let minutes = parse_time_of_day(time).unwrap();
+        NaiveDate::from_ymd_opt(2026, 10, 12 + day)
+            .unwrap()
+            .and_hms_opt(minutes / 60, minutes % 60, 0)
+            .unwrap()
+    }
+
+    const MON: u32 = 0;
+    const THU: u32 = 3;
+    const FRI: u32 = 4;
+    const SAT: u32 = 5;
+    const SUN: u32 = 6;
+
+    #[test]
+    fn daytime_window() {
+        let window = parse_upload_window("mon-fri 08:00-18:00").unwrap();
+        assert!(window.contains(at(MON, "08:00")));
+        assert!(window.contains(at(FRI, "17:59")));
+        assert!(!window.contains(at(MON, "07:59")));
+        assert!(!window.contains(at(MON, "18:00")));
+        assert!(!window.contains(at(SAT, "12:00")));
+    }
+
+    #[test]
+    fn overnight_window_carries_over_to_the_next_day() {
+        let window = parse_upload_window("fri 22:00-06:00").unwrap();
+        assert!(window.contains(at(FRI, "22:00")));
+        assert!(window.contains(at(FRI, "23:59")));
+        assert!(window.contains(at(SAT, "00:00")));
+        assert!(window.contains(at(SAT, "05:59")));
+        assert!(!window.contains(at(SAT, "06:00")));
+        assert!(!window.contains(at(SAT, "22:00")));
+        // Started on Thursday, which is not one of the window's days
+        assert!(!window.contains(at(FRI, "05:00")));
+        assert!(!window.contains(at(THU, "23:00")));
+    }
+
+    #[test]
+    fn overnight_window_on_sunday_carries_over_to_monday() {
+        let window = parse_upload_window("sun 20:00-02:00").unwrap();
+        assert!(window.contains(at(SUN, "21:00")));
+        assert!(window.contains(at(MON + 7, "01:00")));
+        assert!(!window.contains(at(MON, "21:00")));
+    }
+
+    #[test]
+    fn window_until_midnight() {
+        let window = parse_upload_window("sat,sun 00:00-24:00").unwrap();
+        assert_eq!((window.start, window.end), (0, 24 * 60));
+        assert!(window.contains(at(SAT, "00:00")));
+        assert!(window.contains(at(SUN, "23:59")));
+        assert!(!window.contains(at(FRI, "23:59")));
+        assert!(!window.contains(at(MON + 7, "00:00")));
+
+        let window = parse_upload_window("22:00-24:00").unwrap();
+        assert!(window.contains(at(THU, "23:59")));
+        assert!(!window.contains(at(FRI, "00:00")));
+    }
+
+    #[test]
+    fn window_bounds_are_checked() {
+        assert!(parse_upload_window("24:01-06:00").is_err());
+        assert!(parse_upload_window("23:60-06:00").is_err());
+        assert!(parse_upload_window("08:00").is_err());
+        assert!(parse_upload_window("someday 08:00-18:00").is_err());
+    }
+
+    #[test]
+    fn window_with_rate() {
+        let window = parse_upload_window("mon-fri 12:00-13:00=256K").unwrap();
+        assert_eq!(window.rate, Some(256 * 1024));
+        assert_eq!(window.days, [true, true, true, true, true, false, false]);
+        assert_eq!(parse_upload_window("18:00-07:00").unwrap().rate, None);
+        assert!(parse_upload_window("18:00-07:00=0").is_err());
+    }
+
+    #[test]
+    fn weekdays() {
+        assert_eq!(parse_weekdays("mon").unwrap(), [true, false, false, false, false, false, false]);
+        assert_eq!(parse_weekdays("sat,sun").unwrap(), [false, false, false, false, false, true, true]);
+        assert_eq!(parse_weekdays("Monday-Wed,fri").unwrap(), [true, true, true, false, true, false, false]);
+        assert!(parse_weekdays("mon-xyz").is_err());
+    }
+
+    #[test]
+    fn weekday_range_wraps_past_sunday() {
+        assert_eq!(parse_weekdays("fri-mon").unwrap(), [true, false, false, false, true, true, true]);
+        assert_eq!(parse_weekdays("sun-sun").unwrap(), [false, false, false, false, false, false, true]);
+
+        let window = parse_upload_window("fri-mon 09:00-17:00").unwrap();
+        assert!(window.contains(at(SUN, "12:00")));
+        assert!(window.contains(at(MON, "12:00")));
+        assert!(!window.contains(at(THU, "12:00")));
+    }
+}
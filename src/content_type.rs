@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, warn};
+
+use crate::cli::{Config, MismatchPolicy};
+
+/// Bytes read from the start of a file to recognise its format.
+const SNIFF_LEN: u64 = 8 * 1024;
+
+const FALLBACK: &str = "application/octet-stream";
+
+/// A file's content is not of the type its name claims, with `--mime-mismatch reject`.
+#[derive(Debug)]
+pub struct ContentMismatch {
+    pub path: PathBuf,
+    pub claimed: String,
+    pub detected: Option<String>,
+}
+
+impl std::fmt::Display for ContentMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.detected {
+            Some(detected) => write!(
+                f,
+                "{} is named as {} but its content is {}",
+                self.path.display(),
+                self.claimed,
+                detected
+            ),
+            None => write!(
+                f,
+                "{} is named as {} but its content is not recognisable as such",
+                self.path.display(),
+                self.claimed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ContentMismatch {}
+
+/// Decides the MIME type sent with a file.
+///
+/// `--mime-map` overrides come first, then the type recognised from the content's magic bytes,
+/// then the type guessed from the extension. The type the name claims (override or extension)
+/// is checked against the content according to `--mime-mismatch`.
+pub struct ContentTypes {
+    /// MIME types by lower-case extension.
+    overrides: HashMap<String, String>,
+    on_mismatch: MismatchPolicy,
+}
+
+impl ContentTypes {
+    pub fn from_config(config: &Config) -> Self {
+        ContentTypes {
+            overrides: config.mime_map.iter().cloned().collect(),
+            on_mismatch: config.mime_mismatch,
+        }
+    }
+
+    pub async fn detect(&self, path: &Path) -> Result<String> {
+        let mut head = Vec::new();
+        tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Opening file {}", path.display()))?
+            .take(SNIFF_LEN)
+            .read_to_end(&mut head)
+            .await
+            .with_context(|| format!("Reading file {}", path.display()))?;
+
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let overridden = self.overrides.get(&ext).cloned();
+        let claimed = overridden.clone().or_else(|| {
+            mime_guess::from_ext(&ext)
+                .first()
+                .filter(|_| !ext.is_empty())
+                .map(|m| m.to_string())
+        });
+        let detected = infer::get(&head);
+
+        let matches = match (&claimed, detected) {
+            (Some(claimed), Some(kind)) => {
+                claimed == kind.mime_type()
+                    || holds(kind.mime_type(), claimed)
+                    || (overridden.is_none() && extension_fits(&ext, kind))
+            }
+            // Formats with a mandatory signature are a mismatch without one
+            (Some(claimed), None) => {
+                claimed.starts_with("text/") || claimed == FALLBACK || !infer::is_mime_supported(claimed)
+            }
+            (None, _) => true,
+        };
+        if !matches {
+            let mismatch = ContentMismatch {
+                path: path.to_path_buf(),
+                claimed: claimed.clone().unwrap_or_default(),
+                detected: detected.map(|kind| kind.mime_type().to_string()),
+            };
+            match self.on_mismatch {
+                MismatchPolicy::Reject => return Err(mismatch.into()),
+                MismatchPolicy::Warn => warn!("{}", mismatch),
+                MismatchPolicy::Allow => {}
+            }
+        }
+
+        let mime = overridden
+            .or_else(|| detected.map(|kind| kind.mime_type().to_string()))
+            .or(claimed)
+            .unwrap_or_else(|| FALLBACK.to_string());
+        debug!("Content type of {}: {}", path.display(), mime);
+        Ok(mime)
+    }
+}
+
+/// Whether the detected type is a generic container that the claimed format is stored in,
+/// e.g. a .docx that is only recognised as a ZIP archive.
+fn holds(detected: &str, claimed: &str) -> bool {
+    match detected {
+        "application/zip" => {
+            claimed.ends_with("+zip")
+                || claimed.contains("openxmlformats")
+                || claimed.contains("opendocument")
+                || claimed == "application/java-archive"
+        }
+        "application/x-ole-storage" => claimed == "application/msword" || claimed.starts_with("application/vnd.ms-"),
+        "text/xml" => claimed.ends_with("+xml") || claimed == "application/xml",
+        _ => false,
+    }
+}
+
+/// Whether the extension is one used for the detected format, which covers the different
+/// names infer and mime_guess use for some types (e.g. `image/vnd.microsoft.icon` and
+/// `image/x-icon`).
+fn extension_fits(ext: &str, kind: infer::Type) -> bool {
+    kind.extension() == ext
+        || mime_guess::get_mime_extensions_str(kind.mime_type()).is_some_and(|exts| exts.contains(&ext))
+}
@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Files that are never worth uploading: Windows/macOS metadata, Office lock files,
+/// temporary or partially downloaded files, and recycle-bin and snapshot directories
+/// of Windows and common NAS systems.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "$RECYCLE.BIN",
+    "RECYCLER",
+    "System Volume Information",
+    "#recycle",
+    ".recycle",
+    "@Recycle",
+    "#snapshot",
+    ".snapshot",
+    "~snapshot",
+    "@Recently-Snapshot",
+    "Thumbs.db",
+    "ehthumbs.db",
+    "desktop.ini",
+    ".DS_Store",
+    "~$*",
+    "*.tmp",
+    "*.temp",
+    "*.part",
+    "*.crdownload",
+];
+
+/// Include/exclude globs matched against paths relative to the scan root.
+///
+/// Matching follows gitignore conventions: `**` crosses directories, `*` does not,
+/// a pattern without `/` matches a name at any depth, and a leading `/` anchors the
+/// pattern to the scan root. Matching is case-insensitive, as on Windows shares.
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String], default_excludes: bool) -> Result<Self> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build_set(include.iter().map(String::as_str))?)
+        };
+
+        let defaults = if default_excludes { DEFAULT_EXCLUDES } else { &[] };
+        let exclude = build_set(defaults.iter().copied().chain(exclude.iter().map(String::as_str)))?;
+
+        Ok(PathFilter { include, exclude })
+    }
+
+    /// Whether a directory or file is excluded. Excluded directories are not descended into.
+    pub fn is_excluded(&self, rel_path: &str) -> bool {
+        self.exclude.is_match(rel_path)
+    }
+
+    /// Whether a file should be considered at all.
+    pub fn matches_file(&self, rel_path: &str) -> bool {
+        if self.is_excluded(rel_path) {
+            return false;
+        }
+        match &self.include {
+            Some(set) => set.is_match(rel_path),
+            None => true,
+        }
+    }
+}
+
+/// Compile globs with the matching rules described on `PathFilter`.
+pub fn build_set<'a>(patterns: impl Iterator<Item = &'a str>) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let trimmed = pattern.trim().trim_end_matches('/');
+        if trimmed.is_empty() {
+            continue;
+        }
+        let anchored = match trimmed.strip_prefix('/') {
+            Some(rest) => rest.to_string(),
+            None if trimmed.contains('/') => trimmed.to_string(),
+            None => format!("**/{}", trimmed),
+        };
+        // Also match everything below a matching directory
+        for glob in [anchored.clone(), format!("{}/**", anchored)] {
+            builder.add(
+                GlobBuilder::new(&glob)
+                    .literal_separator(true)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Invalid glob pattern {:?}", pattern))?,
+            );
+        }
+    }
+    builder.build().context("Building glob set")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(patterns: &[&str]) -> GlobSet {
+        build_set(patterns.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn name_matches_at_any_depth() {
+        let set = set(&["*.tmp", "Thumbs.db"]);
+        assert!(set.is_match("a.tmp"));
+        assert!(set.is_match("dir/sub/a.TMP"));
+        assert!(set.is_match("dir/thumbs.db"));
+        assert!(!set.is_match("a.tmp.txt"));
+    }
+
+    #[test]
+    fn unanchored_directory_matches_everything_below_it() {
+        let set = set(&["#recycle", "build/"]);
+        assert!(set.is_match("#recycle"));
+        assert!(set.is_match("#recycle/a/b.txt"));
+        assert!(set.is_match("share/#recycle/b.txt"));
+        assert!(set.is_match("src/build/out.o"));
+        assert!(!set.is_match("builder/out.o"));
+    }
+
+    #[test]
+    fn leading_slash_anchors_to_the_root() {
+        let set = set(&["/archive"]);
+        assert!(set.is_match("archive"));
+        assert!(set.is_match("archive/2020/a.txt"));
+        assert!(!set.is_match("old/archive/a.txt"));
+    }
+
+    #[test]
+    fn pattern_with_slash_is_anchored() {
+        let one_level = set(&["docs/*.pdf"]);
+        assert!(one_level.is_match("docs/a.pdf"));
+        assert!(one_level.is_match("DOCS/A.PDF"));
+        assert!(!one_level.is_match("x/docs/a.pdf"));
+        // `*` does not cross directories
+        assert!(!one_level.is_match("docs/sub/a.pdf"));
+
+        let any_level = set(&["docs/**/*.pdf"]);
+        assert!(any_level.is_match("docs/sub/deeper/a.pdf"));
+        assert!(any_level.is_match("docs/a.pdf"));
+    }
+
+    #[test]
+    fn blank_and_invalid_patterns() {
+        assert!(!set(&["", "  ", "/"]).is_match("a.txt"));
+        assert!(build_set(["a["].into_iter()).is_err());
+    }
+}
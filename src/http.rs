@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use reqwest::tls::{Certificate, Identity};
+use reqwest::{NoProxy, Proxy};
+use std::time::Duration;
+use tracing::info;
+
+use crate::cli::Config;
+
+/// Build the HTTP client shared by Google sign-in, rust-alc-api login and all uploads.
+///
+/// Without `--proxy`, proxies come from HTTP_PROXY / HTTPS_PROXY / ALL_PROXY and NO_PROXY.
+/// Extra root CAs are trusted in addition to the built-in ones.
+pub fn build_client(config: &Config) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.read_timeout_secs));
+    if let Some(secs) = config.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+
+    if let Some(url) = &config.proxy {
+        let mut proxy = Proxy::all(url).with_context(|| format!("Invalid --proxy {:?}", redact(url)))?;
+        if let Some(user) = &config.proxy_user {
+            proxy = proxy.basic_auth(user, config.proxy_pass.as_deref().unwrap_or(""));
+        }
+        if let Some(hosts) = &config.no_proxy {
+            proxy = proxy.no_proxy(NoProxy::from_string(hosts));
+        }
+        info!("Using proxy {}", redact(url));
+        builder = builder.proxy(proxy);
+    }
+
+    for path in &config.ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("Reading CA certificate {}", path.display()))?;
+        let certs = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Parsing CA certificate {}", path.display()))?;
+        info!("Trusting {} CA certificate(s) from {}", certs.len(), path.display());
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    if let Some(cert_path) = &config.client_cert {
+        let mut pem = std::fs::read(cert_path)
+            .with_context(|| format!("Reading client certificate {}", cert_path.display()))?;
+        if let Some(key_path) = &config.client_key {
+            pem.push(b'\n');
+            pem.extend(
+                std::fs::read(key_path)
+                    .with_context(|| format!("Reading client key {}", key_path.display()))?,
+            );
+        }
+        let identity = Identity::from_pem(&pem)
+            .with_context(|| {
+                format!(
+                    "Loading client certificate and key from {} (pass --client-key if the key is in a separate file)",
+                    cert_path.display()
+                )
+            })?;
+        info!("Using client certificate {}", cert_path.display());
+        builder = builder.identity(identity);
+    }
+
+    builder.build().context("Building HTTP client")
+}
+
+/// The proxy URL without credentials, for messages.
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        Err(_) => url.rsplit('@').next().unwrap_or(url).to_string(),
+    }
+}
@@ -1,16 +1,31 @@
 mod auth;
 mod cli;
+mod content_type;
+mod filter;
 mod google_auth;
+mod http;
+mod reconcile;
+mod retry;
 mod scanner;
+mod sink;
 mod smb;
 mod state;
+mod throttle;
+mod transform;
 mod uploader;
+mod walk;
+mod watch;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::Utc;
 use clap::Parser;
-use std::collections::HashSet;
+use futures_util::StreamExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tracing::{info, warn};
 
 #[tokio::main]
@@ -24,11 +39,19 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let scan_start = SystemTime::now();
+    for (i, kind) in config.sink.iter().enumerate() {
+        if config.sink[..i].contains(kind) {
+            anyhow::bail!("--sink {} is given more than once", kind.name());
+        }
+    }
+
+    if config.watch && config.command.is_some() {
+        anyhow::bail!("--watch cannot be combined with the reconcile command");
+    }
 
     if let Some(local_path) = &config.local_path {
         info!("Local mode: monitoring {}", local_path.display());
-        run(&config, local_path, scan_start).await
+        run_mode(&config, local_path, true).await
     } else {
         if config.smb_user.is_none() || config.smb_pass.is_none() {
             anyhow::bail!(
@@ -38,7 +61,7 @@ async fn main() -> Result<()> {
         }
         let mount = smb::SmbMount::mount(&config)?;
         let scan_path = PathBuf::from(format!("{}\\{}", mount.drive_letter, config.smb_path));
-        let result = run(&config, &scan_path, scan_start).await;
+        let result = run_mode(&config, &scan_path, false).await;
 
         if let Err(e) = mount.unmount() {
             warn!("Failed to unmount SMB share: {:#}", e);
@@ -48,92 +71,676 @@ async fn main() -> Result<()> {
     }
 }
 
-async fn run(config: &cli::Config, scan_root: &std::path::Path, scan_start: SystemTime) -> Result<()> {
+/// A new path whose content matches a deleted, previously uploaded file.
+struct Rename {
+    from: String,
+    /// Ids of the stored copies by destination.
+    ids: BTreeMap<String, String>,
+    to: scanner::ChangedFile,
+}
+
+/// A configured destination, or why it could not be connected to in this run.
+struct Destination {
+    name: &'static str,
+    sink: std::result::Result<Box<dyn sink::UploadSink>, String>,
+}
+
+impl Destination {
+    fn sink(&self) -> Result<&dyn sink::UploadSink> {
+        self.sink.as_deref().map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+/// Run a single scan, keep scanning in `--watch` mode until interrupted, or reconcile.
+/// Filesystem notifications are only used when `use_notify` is set (not for SMB mounts).
+async fn run_mode(config: &cli::Config, scan_root: &std::path::Path, use_notify: bool) -> Result<()> {
+    let client = http::build_client(config)?;
+    if let Some(cli::Command::Reconcile(args)) = &config.command {
+        return reconcile::run(config, args, &client, scan_root).await;
+    }
+    if !config.watch {
+        // Without a listener Ctrl+C ends the process right away
+        let never = std::pin::pin!(std::future::pending());
+        return run(config, &client, scan_root, SystemTime::now(), never).await.map(|_| ());
+    }
+
+    // The clock probe is written to the watched root on every scan with --watermark probe
+    let ignored = state::state_files(&config.state_file)
+        .into_iter()
+        .chain(std::iter::once(scanner::probe_path(scan_root)))
+        .map(|p| std::path::absolute(&p).unwrap_or(p))
+        .collect();
+    let mut watcher = watch::ChangeWatcher::new(
+        scan_root,
+        use_notify,
+        ignored,
+        Duration::from_secs(config.debounce_secs),
+        Duration::from_secs(config.poll_interval_secs),
+    )?;
+
+    // One listener for the whole session: once it is registered, Ctrl+C no longer ends the
+    // process by itself, so it has to be watched during scans as well as between them
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    loop {
+        let recheck = match run(config, &client, scan_root, SystemTime::now(), interrupted.as_mut()).await {
+            Ok(0) => None,
+            Ok(_) => Some(Duration::from_secs(config.quiet_period_secs.max(1))),
+            Err(e) if e.downcast_ref::<Interrupted>().is_some() => {
+                info!("Interrupted, stopping watch mode");
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Scan failed, will retry on next change: {:#}", e);
+                None
+            }
+        };
+        if !watcher.wait(recheck, interrupted.as_mut()).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Watch mode was interrupted with Ctrl+C during a run.
+#[derive(Debug)]
+struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Scan once and upload. Returns the number of files deferred because they were still
+/// being written, so watch mode can rescan once they have settled.
+///
+/// When `interrupted` completes, the run stops with `Interrupted`; uploads that finished by
+/// then are recorded, renames and deletions are left for the next run.
+async fn run<F>(
+    config: &cli::Config,
+    client: &reqwest::Client,
+    scan_root: &std::path::Path,
+    scan_start: SystemTime,
+    mut interrupted: Pin<&mut F>,
+) -> Result<usize>
+where
+    F: Future<Output = std::io::Result<()>>,
+{
     let failed_list_path = state::failed_list_path(&config.state_file);
+    let scan_options = Arc::new(scanner::ScanOptions::from_config(config)?);
 
-    // 1. Load previously failed files (retry candidates)
-    let mut retry_candidates = state::load_failed_list(&failed_list_path)?;
-    if !retry_candidates.is_empty() {
-        info!("{} file(s) pending retry from previous run", retry_candidates.len());
-        retry_candidates.retain(|p| p.exists());
+    let destinations: Vec<&'static str> = config.sink.iter().map(|kind| kind.name()).collect();
+
+    // 1. Load previously failed uploads (retry candidates) and the dead-letter list, per destination
+    let mut retries: HashMap<(PathBuf, String), state::FailedFile> =
+        per_destination(state::load_failed_list(&failed_list_path)?, &destinations)
+            .into_iter()
+            .map(|entry| (retry_key(&entry), entry))
+            .collect();
+    if !retries.is_empty() {
+        info!("{} upload(s) pending retry from previous run", retries.len());
+    }
+    let dead_letter_path = state::dead_letter_path(&config.state_file);
+    let mut dead_letters = per_destination(state::load_failed_list(&dead_letter_path)?, &destinations);
+    for path in &config.requeue {
+        if !dead_letters.iter().any(|entry| &entry.path == path) {
+            warn!("--requeue {}: not in the dead-letter list", path.display());
+        }
+    }
+    // Requeued files get a fresh attempt budget
+    dead_letters.retain(|entry| {
+        let requeue = config.requeue_all || config.requeue.contains(&entry.path);
+        if requeue {
+            info!("Requeued dead-lettered file: {} ({})", entry.path.display(), destination_name(entry));
+            retries.insert(retry_key(entry), state::FailedFile { attempts: 0, ..entry.clone() });
+        }
+        !requeue
+    });
+    retries.retain(|(path, _), entry| keep_if_exists(path, entry, "retry"));
+    dead_letters.retain(|entry| keep_if_exists(&entry.path, entry, "dead-lettered file"));
+    let unconfigured = retries
+        .keys()
+        .filter(|(_, d)| !destinations.contains(&d.as_str()))
+        .count();
+    if unconfigured > 0 {
+        info!("Keeping {} retry(ies) for destinations not configured in this run", unconfigured);
+    }
+    let queued_path = state::queued_files_path(&config.state_file);
+    let mut queued: HashSet<(PathBuf, String)> = state::load_queued_files(&queued_path)?
+        .into_iter()
+        .filter(|(path, destination)| {
+            let exists = path.exists();
+            if !exists {
+                info!("Dropping queued upload {} ({}): file no longer exists", path.display(), destination);
+            }
+            exists
+        })
+        .collect();
+    if !queued.is_empty() {
+        info!("{} upload(s) queued from outside the upload windows", queued.len());
     }
 
     // 2. Resolve "since" threshold
+    let last_watermark = state::read_last_run(&config.state_file)?;
     let since: SystemTime = if let Some(dt) = config.since {
         info!("Using --since override: {}", dt.to_rfc3339());
         SystemTime::from(dt)
     } else {
-        state::read_last_run(&config.state_file)?
+        let overlap = Duration::from_secs(config.watermark_overlap_mins.saturating_mul(60));
+        last_watermark
+            .checked_sub(overlap)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .max(SystemTime::UNIX_EPOCH)
+    };
+
+    let probe_time = if config.watermark == cli::WatermarkSource::Probe {
+        let root = scan_root.to_path_buf();
+        match blocking(&scan_options, interrupted.as_mut(), move || scanner::probe_server_time(&root)).await? {
+            Ok(t) => Some(t),
+            Err(e) => {
+                warn!("Clock probe failed, using the highest observed mtime instead: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let manifest_path = state::manifest_path(&config.state_file);
+    let loaded_manifest = state::load_manifest(&manifest_path)?;
+    let seed = loaded_manifest.is_none();
+    if seed && since > SystemTime::UNIX_EPOCH {
+        info!("Seeding manifest with files not modified since the last run");
+    }
+    let manifest = loaded_manifest.unwrap_or_default();
+
+    let dir_cache_path = state::dir_cache_path(&config.state_file);
+    let dir_cache = if config.incremental {
+        Some(state::load_dir_cache(&dir_cache_path)?)
+    } else {
+        None
     };
     info!("Scanning: {}", scan_root.display());
 
-    let changed_files = scanner::find_changed_files(scan_root, since)?;
+    let scan_task = {
+        let (root, options) = (scan_root.to_path_buf(), Arc::clone(&scan_options));
+        let (mut manifest, mut dir_cache) = (manifest, dir_cache);
+        move || {
+            let scan = scanner::find_changed_files(&root, &options, since, &mut manifest, seed, dir_cache.as_mut());
+            (scan, manifest, dir_cache)
+        }
+    };
+    let (scan, mut manifest, dir_cache) = blocking(&scan_options, interrupted.as_mut(), scan_task).await?;
+    let scan = scan?;
+    let mut deferred = scan.deferred;
+
+    let watermark = match config.watermark {
+        cli::WatermarkSource::Local => scan_start,
+        _ => probe_time.or(scan.max_mtime).unwrap_or(last_watermark),
+    };
 
-    // 3. Merge: changed files + retries, deduplicated
-    let retry_set: HashSet<PathBuf> = retry_candidates.into_iter().collect();
-    let mut all_files: Vec<PathBuf> = changed_files;
-    for p in &retry_set {
-        if !all_files.contains(p) {
-            info!("Adding retry: {}", p.display());
-            all_files.push(p.clone());
+    // 3. Merge: changed files + retries + queued uploads, each with the destinations it is pending for
+    let changed_paths: HashSet<PathBuf> = scan.changed.iter().map(|f| f.path.clone()).collect();
+    let scanned: Vec<(PathBuf, String, String)> = scan
+        .changed
+        .iter()
+        .map(|f| (f.path.clone(), f.rel_path.clone(), f.entry.sha256.clone()))
+        .collect();
+    let mut changed_files: Vec<scanner::ChangedFile> = scan.changed;
+    let mut retry_paths: Vec<PathBuf> = retries
+        .keys()
+        .chain(queued.iter())
+        .filter(|(_, d)| destinations.contains(&d.as_str()))
+        .map(|(p, _)| p.clone())
+        .collect();
+    retry_paths.sort();
+    retry_paths.dedup();
+    for p in &retry_paths {
+        if !changed_paths.contains(p) {
+            let check = {
+                let (root, path, options) = (scan_root.to_path_buf(), p.clone(), Arc::clone(&scan_options));
+                blocking(&scan_options, interrupted.as_mut(), move || scanner::describe_file(&root, &path, &options))
+                    .await?
+            };
+            match check {
+                Ok(scanner::FileCheck::Ready(file)) => {
+                    info!("Adding retry: {}", p.display());
+                    changed_files.push(file);
+                }
+                Ok(scanner::FileCheck::Skipped) => {
+                    info!("Dropping retry: {}", p.display());
+                    retries.retain(|(path, _), _| path != p);
+                    queued.retain(|(path, _)| path != p);
+                }
+                Ok(scanner::FileCheck::Deferred) => deferred += 1,
+                Err(e) => {
+                    warn!("Dropping retry {}: {:#}", p.display(), e);
+                    retries.retain(|(path, _), _| path != p);
+                    queued.retain(|(path, _)| path != p);
+                }
+            }
+        }
+    }
+    // Changed files go to every destination, retries and queued uploads only where pending
+    let mut all_files: Vec<(scanner::ChangedFile, Vec<&'static str>)> = changed_files
+        .into_iter()
+        .filter_map(|file| {
+            let targets: Vec<&'static str> = destinations
+                .iter()
+                .copied()
+                .filter(|d| {
+                    let key = (file.path.clone(), d.to_string());
+                    changed_paths.contains(&file.path) || retries.contains_key(&key) || queued.contains(&key)
+                })
+                .filter(|d| {
+                    let dead = dead_letters
+                        .iter()
+                        .any(|e| e.path == file.path && e.destination.as_deref() == Some(*d));
+                    if dead {
+                        info!("Skipping {} for {}: in the dead-letter list", file.path.display(), d);
+                    }
+                    !dead
+                })
+                .collect();
+            (!targets.is_empty()).then_some((file, targets))
+        })
+        .collect();
+    let retried = all_files.iter().filter(|(f, _)| !changed_paths.contains(&f.path)).count();
+
+    // 4. Match new files against deleted ones by content: the same hash means a rename.
+    // Stored copies are transformed output, so both paths must also share their transforms.
+    let transforms = transform::Transforms::from_config(config)?;
+    let mut vanished = scan.vanished;
+    let mut renames: Vec<Rename> = Vec::new();
+    all_files.retain_mut(|(file, targets)| {
+        if manifest.files.contains_key(&file.rel_path) {
+            return true;
+        }
+        let matched = vanished
+            .iter()
+            .position(|(rel, e)| {
+                !e.ids.is_empty() && e.sha256 == file.entry.sha256 && transforms.same_steps(rel, &file.rel_path)
+            });
+        match matched {
+            Some(i) => {
+                let (from, entry) = vanished.remove(i);
+                info!("Renamed: {} -> {}", from, file.rel_path);
+                // Destinations without a stored copy to rename get a fresh upload
+                targets.retain(|d| !entry.ids.contains_key(*d));
+                renames.push(Rename {
+                    from,
+                    ids: entry.ids,
+                    to: file.clone(),
+                });
+                !targets.is_empty()
+            }
+            None => true,
+        }
+    });
+
+    // Deletions that need no server call are only logged
+    let mut removals: Vec<(String, BTreeMap<String, String>)> = Vec::new();
+    for (rel, entry) in vanished {
+        let ids: BTreeMap<String, String> = entry
+            .ids
+            .into_iter()
+            .filter(|(d, _)| destinations.contains(&d.as_str()))
+            .collect();
+        if config.on_delete != cli::DeleteAction::Log && !ids.is_empty() {
+            removals.push((rel, ids));
+        } else {
+            info!("Deleted locally: {} (server copy kept)", rel);
+            if !config.dry_run {
+                manifest.files.remove(&rel);
+            }
         }
     }
 
     let files_found = all_files.len();
-    info!("Found {} file(s) to process ({} new/changed + {} retries)",
+    info!("Found {} file(s) to process ({} new/changed + {} retries), {} rename(s), {} deletion(s)",
         files_found,
-        files_found - retry_set.len().min(files_found),
-        retry_set.len().min(files_found),
+        files_found - retried,
+        retried,
+        renames.len(),
+        removals.len(),
     );
 
-    let mut uploaded = 0usize;
-    let mut new_failed: Vec<PathBuf> = Vec::new();
+    let mut results: Vec<state::DestinationResult> = destinations
+        .iter()
+        .map(|d| state::DestinationResult { name: d.to_string(), uploaded: 0, failed: 0, transform_failed: 0 })
+        .collect();
+    let mut failures: Vec<(PathBuf, String, String)> = Vec::new();
+    let mut transform_failed_paths: HashSet<PathBuf> = HashSet::new();
+    let mut deferred_paths: HashSet<PathBuf> = HashSet::new();
+    let mut queued_paths: HashSet<PathBuf> = HashSet::new();
+    let mut stopped = false;
+    let throttle = throttle::Throttle::from_config(config);
 
-    if files_found == 0 {
+    if files_found == 0 && renames.is_empty() && removals.is_empty() {
         info!("No files to process");
     } else if config.dry_run {
         info!("Dry run mode: skipping uploads");
-        for path in &all_files {
-            info!("  Would upload: {}", path.display());
+        if !throttle.is_open() {
+            info!("Outside the upload windows: uploads would be queued");
+        }
+        for (file, targets) in &all_files {
+            info!("  Would upload to {}: {}", targets.join(", "), file.path.display());
+            if let Some(steps) = transforms.steps(&file.rel_path) {
+                let steps: Vec<String> = steps.iter().map(ToString::to_string).collect();
+                info!("    after transforming with {} as {}", steps.join(", "), transforms.target_path(&file.rel_path));
+            }
+        }
+        for rename in &renames {
+            info!("  Would rename: {} -> {}", rename.from, rename.to.rel_path);
+        }
+        for (rel, ids) in &removals {
+            for (destination, id) in ids {
+                info!("  Would {:?} {} copy of {} (id: {})", config.on_delete, destination, rel, id);
+            }
+        }
+    } else if !throttle.is_open() {
+        // Nothing is sent until a window opens; renamed and deleted files are detected again then
+        info!("Outside the upload windows: queueing {} file(s) for the next window", files_found + renames.len());
+        for (file, targets) in &all_files {
+            for destination in targets {
+                queued.insert((file.path.clone(), destination.to_string()));
+            }
+            queued_paths.insert(file.path.clone());
+        }
+        for rename in &renames {
+            for destination in &destinations {
+                queued.insert((rename.to.path.clone(), destination.to_string()));
+            }
+        }
+        if !removals.is_empty() {
+            info!("{} deletion(s) will be applied in the next window", removals.len());
         }
     } else {
-        let client = uploader::build_client()?;
-
-        // Google Device Flow → rust-alc-api で認証
-        let id_token = google_auth::device_flow_get_id_token(
-            &client,
-            &config.google_client_id,
-            &config.google_client_secret,
-        ).await?;
+        if let throttle::Limit::BytesPerSec(rate) = throttle.limit() {
+            info!("Upload bandwidth limited to {} bytes/s", rate);
+        }
+        let mut sinks: Vec<Destination> = Vec::new();
+        for kind in &config.sink {
+            // A destination that cannot be reached fails its own uploads, not the others'
+            let sink = sink::connect(*kind, config, scan_root, client, &throttle).await.map_err(|e| {
+                warn!("Cannot connect to {}: {:#}", kind.name(), e);
+                format!("Connecting to {}: {:#}", kind.name(), e)
+            });
+            sinks.push(Destination { name: kind.name(), sink });
+        }
+        let sinks = &sinks;
+        let throttle = &throttle;
+        let transforms = &transforms;
 
-        let auth_url = format!("{}/api/auth/google", config.alc_api_url.trim_end_matches('/'));
-        let (token, tenant_id) = auth::login_with_google(&client, &auth_url, &id_token).await?;
-        info!("Authenticated: tenant_id={}", tenant_id);
+        // Uploads are numbered per destination; each file's destinations are sent one by one
+        let mut total = 0;
+        let tasks: Vec<(&scanner::ChangedFile, Vec<(usize, usize)>)> = all_files
+            .iter()
+            .map(|(file, targets)| {
+                let uploads = targets
+                    .iter()
+                    .map(|d| {
+                        total += 1;
+                        (total - 1, sinks.iter().position(|s| s.name == *d).unwrap_or_default())
+                    })
+                    .collect();
+                (file, uploads)
+            })
+            .collect();
 
-        let upload_url = format!("{}/api/files", config.alc_api_url.trim_end_matches('/'));
+        let retry_policy = retry::RetryPolicy::from_config(config);
+        if config.concurrency > 1 {
+            info!("Uploading with {} parallel connections", config.concurrency);
+        }
+        let mut outcomes = futures_util::stream::iter(tasks)
+            .map(|(file, uploads)| {
+                // Transformed once, before the first upload, and shared by all destinations.
+                // Each upload's outcome is yielded as soon as it is known.
+                let prepared: Option<Result<transform::Prepared, Arc<anyhow::Error>>> = None;
+                let state = (uploads.into_iter(), prepared);
+                Box::pin(futures_util::stream::unfold(state, move |(mut uploads, mut prepared)| async move {
+                    let (i, dest) = uploads.next()?;
+                    let name = sinks[dest].name;
+                    info!("Uploading {}/{} to {}: {}", i + 1, total, name, file.path.display());
+                    let label = format!("[{}/{}] {} -> {}", i + 1, total, file.path.display(), name);
+                    let result = match sinks[dest].sink() {
+                        // A window that closed during the run stops new uploads, not running ones
+                        Ok(_) if !throttle.is_open() => Err(Arc::new(throttle::OutsideWindow.into())),
+                        // The transformed file is uploaded; the manifest records the scanned one
+                        Ok(sink) => {
+                            if prepared.is_none() {
+                                prepared = Some(transforms.prepare(file).await.map_err(Arc::new));
+                            }
+                            match prepared.as_ref().expect("prepared above") {
+                                Ok(prepared) => retry_policy
+                                    .run(&label, || async {
+                                        let id = sink.upload(prepared).await?;
+                                        if config.verify {
+                                            sink::verify(sink, name, id.as_deref(), prepared).await?;
+                                        }
+                                        Ok(id)
+                                    })
+                                    .await
+                                    .map_err(Arc::new),
+                                Err(e) => Err(Arc::clone(e)),
+                            }
+                        }
+                        Err(e) => Err(Arc::new(e)),
+                    };
+                    Some(((i, file, dest, result), (uploads, prepared)))
+                }))
+            })
+            .flatten_unordered(config.concurrency as usize);
 
-        for (i, path) in all_files.iter().enumerate() {
-            info!("Uploading {}/{}: {}", i + 1, files_found, path.display());
-            match uploader::upload_file(&client, &upload_url, path, &token).await {
-                Ok(()) => uploaded += 1,
+        loop {
+            let outcome = tokio::select! {
+                outcome = outcomes.next() => outcome,
+                _ = interrupted.as_mut() => {
+                    info!("Interrupted: stopping uploads, recording the ones that finished");
+                    stopped = true;
+                    None
+                }
+            };
+            let Some((i, file, dest, result)) = outcome else { break };
+            let name = sinks[dest].name;
+            let key = (file.path.clone(), name.to_string());
+            match result {
+                Ok(id) => {
+                    results[dest].uploaded += 1;
+                    retries.remove(&key);
+                    queued.remove(&key);
+                    record_upload(&mut manifest, file, name, id);
+                }
+                Err(e) if e.downcast_ref::<throttle::OutsideWindow>().is_some() => {
+                    queued.insert(key);
+                    queued_paths.insert(file.path.clone());
+                }
+                Err(e) if e.downcast_ref::<uploader::FileUnstable>().is_some() => {
+                    info!("[{}/{}] Deferred to next run: {:#}", i + 1, total, e);
+                    deferred_paths.insert(file.path.clone());
+                }
+                Err(e) if e.downcast_ref::<transform::TransformFailed>().is_some() => {
+                    warn!("[{}/{}] {} (not uploaded to {})", i + 1, total, e, name);
+                    results[dest].transform_failed += 1;
+                    queued.remove(&key);
+                    transform_failed_paths.insert(file.path.clone());
+                    failures.push((file.path.clone(), name.to_string(), format!("{:#}", e)));
+                }
                 Err(e) => {
-                    warn!("Failed: {}: {:#}", path.display(), e);
-                    new_failed.push(path.clone());
+                    warn!("[{}/{}] Failed: {} -> {}: {:#}", i + 1, total, file.path.display(), name, e);
+                    results[dest].failed += 1;
+                    queued.remove(&key);
+                    failures.push((file.path.clone(), name.to_string(), format!("{:#}", e)));
                 }
             }
         }
+        drop(outcomes);
+        if stopped {
+            // Renames and deletions wait for the next run
+            renames.clear();
+            removals.clear();
+        }
+
+        if !queued_paths.is_empty() {
+            info!("Upload window closed: {} file(s) queued for the next window", queued_paths.len());
+        }
+        for result in &results {
+            if result.failed > 0 {
+                warn!("{}: {} upload(s) failed", result.name, result.failed);
+            }
+            if result.transform_failed > 0 {
+                warn!("{}: {} file(s) not uploaded because a transform failed", result.name, result.transform_failed);
+            }
+        }
+
+        // Renames that fail everywhere keep their manifest entries and are retried next run.
+        // Where only some destinations fail, those get a fresh upload of the new path instead.
+        for rename in renames {
+            let mut ids = BTreeMap::new();
+            let mut renamed = 0usize;
+            let mut rename_failures = Vec::new();
+            for (destination, id) in rename.ids {
+                let Some(target) = sinks.iter().find(|s| s.name == destination) else {
+                    // Not configured in this run: the copy keeps its id under the new path
+                    ids.insert(destination, id);
+                    continue;
+                };
+                let result = match target.sink() {
+                    Ok(sink) => sink.rename(&id, &transforms.target_path(&rename.to.rel_path)).await,
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(new_id) => {
+                        info!("Renamed on {}: {} -> {}", destination, rename.from, rename.to.rel_path);
+                        // A rename queued outside the upload windows is done now
+                        queued.remove(&(rename.to.path.clone(), destination.clone()));
+                        ids.insert(destination, new_id);
+                        renamed += 1;
+                    }
+                    Err(e) => {
+                        warn!("Failed to rename on {}: {} -> {}: {:#}", destination, rename.from, rename.to.rel_path, e);
+                        rename_failures.push((destination, format!("Renaming from {}: {:#}", rename.from, e)));
+                    }
+                }
+            }
+            if renamed == 0 && !rename_failures.is_empty() {
+                continue;
+            }
+            for (destination, error) in rename_failures {
+                warn!("Uploading {} to {} again next run; the copy under the old name is left there", rename.to.rel_path, destination);
+                queued.remove(&(rename.to.path.clone(), destination.clone()));
+                failures.push((rename.to.path.clone(), destination, error));
+            }
+            manifest.files.remove(&rename.from);
+            manifest
+                .files
+                .entry(rename.to.rel_path.clone())
+                .or_insert_with(|| rename.to.entry.clone())
+                .ids
+                .extend(ids);
+        }
+
+        // Failed deletions keep their ids and are retried next run
+        for (rel, ids) in removals {
+            let mut remaining = BTreeMap::new();
+            for (destination, id) in ids {
+                let result = match sinks.iter().find(|s| s.name == destination).map(Destination::sink) {
+                    Some(Ok(sink)) => match config.on_delete {
+                        cli::DeleteAction::Delete => sink.delete(&id).await,
+                        _ => sink.archive(&id).await,
+                    },
+                    Some(Err(e)) => Err(e),
+                    None => Ok(()),
+                };
+                match result {
+                    Ok(()) => info!("Applied {:?} to {} copy of deleted file {}", config.on_delete, destination, rel),
+                    Err(e) => {
+                        warn!("Failed to {:?} {} copy of {}: {:#}", config.on_delete, destination, rel, e);
+                        remaining.insert(destination, id);
+                    }
+                }
+            }
+            match manifest.files.get_mut(&rel) {
+                Some(entry) if !remaining.is_empty() => entry.ids = remaining,
+                _ => {
+                    manifest.files.remove(&rel);
+                }
+            }
+        }
+
+        for destination in sinks {
+            if let Ok(sink) = &destination.sink {
+                sink.finish()?;
+            }
+        }
+    }
 
-        if !new_failed.is_empty() {
-            warn!("{} file(s) failed; will retry next run", new_failed.len());
+    let failed_paths: HashSet<&PathBuf> = failures.iter().map(|(path, _, _)| path).collect();
+    // Files whose transforms failed are counted apart from failed uploads
+    let failed_count = failed_paths.iter().filter(|path| !transform_failed_paths.contains(**path)).count();
+    let transform_failed = transform_failed_paths.len();
+    let uploaded = if config.dry_run {
+        0
+    } else {
+        all_files
+            .iter()
+            .filter(|(f, _)| {
+                !failed_paths.contains(&f.path)
+                    && !deferred_paths.contains(&f.path)
+                    && !queued_paths.contains(&f.path)
+            })
+            .count()
+    };
+    deferred += deferred_paths.len();
+
+    // 5. Record failures; files that keep failing move to the dead-letter list
+    let retry_list = record_failures(config, retries, &mut dead_letters, failures);
+
+    if !dead_letters.is_empty() {
+        warn!(
+            "{} file(s) in the dead-letter list are not retried (requeue with --requeue PATH or --requeue-all):",
+            dead_letters.len()
+        );
+        for entry in &dead_letters {
+            warn!(
+                "  {} -> {} ({} failed run(s) since {}, last error: {})",
+                entry.path.display(),
+                destination_name(entry),
+                entry.attempts,
+                entry.first_failure.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                entry.last_error
+            );
         }
     }
 
-    let failed_count = new_failed.len();
+    // 6. Save updated failed lists and manifest
+    state::save_failed_list(&failed_list_path, &retry_list)?;
+    state::save_failed_list(&dead_letter_path, &dead_letters)?;
+    let mut queued: Vec<(PathBuf, String)> = queued.into_iter().collect();
+    queued.sort();
+    state::save_queued_files(&queued_path, &queued)?;
+    state::save_manifest(&manifest_path, &manifest)?;
+    if let Some(mut cache) = dir_cache.filter(|_| !config.dry_run) {
+        // A reused listing hides its files from later scans, so directories with changes
+        // that were neither uploaded nor queued for another attempt are listed again
+        for (path, rel_path, sha256) in &scanned {
+            let recorded = manifest.files.get(rel_path).is_some_and(|entry| &entry.sha256 == sha256)
+                || retry_list.iter().chain(&dead_letters).any(|entry| &entry.path == path)
+                || queued.iter().any(|(queued, _)| queued == path);
+            if !recorded {
+                cache.dirs.remove(scanner::parent_rel(rel_path));
+            }
+        }
+        state::save_dir_cache(&dir_cache_path, &cache)?;
+    }
 
-    // 4. Save updated failed list
-    state::save_failed_list(&failed_list_path, &new_failed)?;
+    if stopped {
+        return Err(Interrupted.into());
+    }
 
-    // 5. Record run
+    // 7. Record run
     state::append_run_record(
         &config.state_file,
         &state::RunRecord {
@@ -142,9 +749,129 @@ async fn run(config: &cli::Config, scan_root: &std::path::Path, scan_start: Syst
             files_found,
             uploaded,
             failed: failed_count,
+            transform_failed,
+            deferred,
+            watermark,
             dry_run: config.dry_run,
+            destinations: results,
         },
     )?;
 
-    Ok(())
+    Ok(deferred)
+}
+
+/// Count failures (path, destination, error) against their retry entries and move entries
+/// that reached `--dead-letter-after` failed runs to the dead-letter list. Returns the
+/// remaining retries; both lists are sorted for saving.
+fn record_failures(
+    config: &cli::Config,
+    mut retries: HashMap<(PathBuf, String), state::FailedFile>,
+    dead_letters: &mut Vec<state::FailedFile>,
+    failures: Vec<(PathBuf, String, String)>,
+) -> Vec<state::FailedFile> {
+    let now = Utc::now();
+    for (path, destination, error) in failures {
+        let key = (path.clone(), destination.clone());
+        let entry = retries.entry(key).or_insert_with(|| state::FailedFile {
+            path,
+            destination: Some(destination),
+            attempts: 0,
+            first_failure: now,
+            last_failure: now,
+            last_error: String::new(),
+        });
+        entry.attempts += 1;
+        entry.last_failure = now;
+        entry.last_error = error;
+    }
+    let (dead, mut retry_list): (Vec<_>, Vec<_>) = retries
+        .into_values()
+        .partition(|entry| entry.attempts >= config.dead_letter_after);
+    for entry in dead {
+        warn!(
+            "Moved {} ({}) to the dead-letter list after {} failed run(s)",
+            entry.path.display(),
+            destination_name(&entry),
+            entry.attempts
+        );
+        dead_letters.push(entry);
+    }
+    retry_list.sort_by_key(retry_key);
+    dead_letters.sort_by_key(retry_key);
+    retry_list
+}
+
+/// Run blocking file system work (listings, stats and hashing are round trips over SMB) on
+/// the blocking thread pool, so that an interrupt is noticed meanwhile. An interrupt cancels
+/// the scan and returns `Interrupted`.
+async fn blocking<T, F>(
+    options: &scanner::ScanOptions,
+    interrupted: Pin<&mut F>,
+    work: impl FnOnce() -> T + Send + 'static,
+) -> Result<T>
+where
+    T: Send + 'static,
+    F: Future<Output = std::io::Result<()>>,
+{
+    tokio::select! {
+        result = tokio::task::spawn_blocking(work) => result.context("Scan task panicked"),
+        _ = interrupted => {
+            options.cancelled.store(true, std::sync::atomic::Ordering::Relaxed);
+            Err(Interrupted.into())
+        }
+    }
+}
+
+/// Drop a failed-file entry whose file no longer exists, saying so in the log.
+fn keep_if_exists(path: &std::path::Path, entry: &state::FailedFile, kind: &str) -> bool {
+    let exists = path.exists();
+    if !exists {
+        info!(
+            "Dropping {} {}: file no longer exists ({} failed run(s), last error: {})",
+            kind,
+            path.display(),
+            entry.attempts,
+            entry.last_error
+        );
+    }
+    exists
+}
+
+/// Expand failed-file entries written before per-destination tracking to every destination.
+fn per_destination(entries: Vec<state::FailedFile>, destinations: &[&str]) -> Vec<state::FailedFile> {
+    entries
+        .into_iter()
+        .flat_map(|entry| match entry.destination {
+            Some(_) => vec![entry],
+            None => destinations
+                .iter()
+                .map(|d| state::FailedFile { destination: Some(d.to_string()), ..entry.clone() })
+                .collect(),
+        })
+        .collect()
+}
+
+fn retry_key(entry: &state::FailedFile) -> (PathBuf, String) {
+    (entry.path.clone(), destination_name(entry).to_string())
+}
+
+fn destination_name(entry: &state::FailedFile) -> &str {
+    entry.destination.as_deref().unwrap_or_default()
+}
+
+/// Record a successful upload to `destination`, keeping the ids of the other destinations.
+fn record_upload(manifest: &mut state::Manifest, file: &scanner::ChangedFile, destination: &str, id: Option<String>) {
+    let mut ids = manifest
+        .files
+        .remove(&file.rel_path)
+        .map(|entry| entry.ids)
+        .unwrap_or_default();
+    match id {
+        Some(id) => ids.insert(destination.to_string(), id),
+        None => ids.remove(destination),
+    };
+    manifest.files.insert(
+        file.rel_path.clone(),
+        state::ManifestEntry { ids, ..file.entry.clone() },
+    );
 }
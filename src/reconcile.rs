@@ -0,0 +1,330 @@
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::cli::{Config, ReconcileArgs, SinkKind};
+use crate::scanner::{self, ChangedFile};
+use crate::sink::{self, AlcApiSink, UploadSink};
+use crate::throttle::{self, Throttle};
+use crate::transform::{self, Transforms};
+use crate::uploader::{self, RemoteFile};
+use crate::{retry, state};
+
+/// How a path compares between the share and the server. Paths whose local content matches
+/// a copy on the server are not reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    /// On the share, but the server holds nothing under this path.
+    Missing,
+    /// On the server, but not (or no longer) on the share.
+    RemoteOnly,
+    /// On both, but no copy on the server has the local content.
+    Differs,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Missing => "missing",
+            Status::RemoteOnly => "remote-only",
+            Status::Differs => "differs",
+        }
+    }
+}
+
+struct Finding<'a> {
+    status: Status,
+    rel_path: String,
+    local: Option<&'a ChangedFile>,
+    remote: Vec<&'a RemoteFile>,
+}
+
+/// Compare the share with what rust-alc-api holds, write the report, and optionally upload
+/// what the server lacks. The watermark is neither used nor updated.
+pub async fn run(config: &Config, args: &ReconcileArgs, client: &reqwest::Client, scan_root: &Path) -> Result<()> {
+    let options = scanner::ScanOptions::from_config(config)?;
+    info!("Reconcile: hashing every file in {}", scan_root.display());
+    let scan = scanner::hash_tree(scan_root, &options)?;
+    if scan.errors > 0 {
+        warn!("{} entries could not be read and are left out of the comparison", scan.errors);
+    }
+    if scan.deferred > 0 {
+        warn!("{} file(s) still being written are left out of the comparison", scan.deferred);
+    }
+
+    let throttle = Throttle::from_config(config);
+    let transforms = Transforms::from_config(config)?;
+    let alc_api = AlcApiSink::connect(config, scan_root, client.clone(), throttle.clone()).await?;
+    let remote = alc_api.list_files().await?;
+    info!("Server holds {} file(s); {} file(s) on the share", remote.len(), scan.changed.len());
+
+    let manifest_path = state::manifest_path(&config.state_file);
+    let loaded_manifest = state::load_manifest(&manifest_path)?;
+    let has_manifest = loaded_manifest.is_some();
+    let mut manifest = loaded_manifest.unwrap_or_default();
+
+    // Files uploaded without a relative path are placed by the manifest, or else by file name
+    let recorded: HashMap<String, String> = manifest
+        .files
+        .iter()
+        .filter_map(|(rel, entry)| entry.ids.get(SinkKind::AlcApi.name()).map(|id| (id.clone(), rel.clone())))
+        .collect();
+    let mut by_path: BTreeMap<String, Vec<&RemoteFile>> = BTreeMap::new();
+    for file in &remote {
+        let rel_path = file
+            .relative_path
+            .clone()
+            .or_else(|| recorded.get(&file.uuid).cloned())
+            .or_else(|| file.filename.clone());
+        match rel_path {
+            Some(rel_path) if options.filter.matches_file(&rel_path) => by_path.entry(rel_path).or_default().push(file),
+            Some(_) => {}
+            None => warn!("Ignoring server file {}: it has neither a relative path nor a file name", file.uuid),
+        }
+    }
+
+    let mut findings: Vec<Finding> = Vec::new();
+    let mut matched = 0usize;
+    for local in &scan.changed {
+        // Transformed files are stored under their target name with other content, so any
+        // copy there counts as a match
+        if transforms.steps(&local.rel_path).is_some() {
+            match by_path.remove(&transforms.target_path(&local.rel_path)) {
+                Some(copies) => {
+                    matched += 1;
+                    record_match(&mut manifest, local, copies[0]);
+                }
+                None => findings.push(Finding {
+                    status: Status::Missing,
+                    rel_path: local.rel_path.clone(),
+                    local: Some(local),
+                    remote: Vec::new(),
+                }),
+            }
+            continue;
+        }
+        match by_path.remove(&local.rel_path) {
+            None => findings.push(Finding {
+                status: Status::Missing,
+                rel_path: local.rel_path.clone(),
+                local: Some(local),
+                remote: Vec::new(),
+            }),
+            Some(copies) => match copies.iter().find(|copy| same_content(local, copy)) {
+                Some(copy) => {
+                    matched += 1;
+                    record_match(&mut manifest, local, copy);
+                }
+                None => findings.push(Finding {
+                    status: Status::Differs,
+                    rel_path: local.rel_path.clone(),
+                    local: Some(local),
+                    remote: copies,
+                }),
+            },
+        }
+    }
+    findings.extend(by_path.into_iter().map(|(rel_path, copies)| Finding {
+        status: Status::RemoteOnly,
+        rel_path,
+        local: None,
+        remote: copies,
+    }));
+    findings.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+
+    for finding in &findings {
+        info!("  {}: {}", finding.status.name(), finding.rel_path);
+    }
+    let count = |status: Status| findings.iter().filter(|f| f.status == status).count();
+    info!(
+        "Reconcile: {} matching, {} missing on the server, {} only on the server, {} differing",
+        matched,
+        count(Status::Missing),
+        count(Status::RemoteOnly),
+        count(Status::Differs)
+    );
+    let report_path = args
+        .report
+        .clone()
+        .unwrap_or_else(|| state::reconcile_report_path(&config.state_file));
+    write_report(&report_path, &findings)?;
+
+    let failed_list_path = state::failed_list_path(&config.state_file);
+    let dead_letter_path = state::dead_letter_path(&config.state_file);
+    let destinations: Vec<&str> = config.sink.iter().map(|kind| kind.name()).collect();
+    let mut retries: HashMap<(PathBuf, String), state::FailedFile> =
+        crate::per_destination(state::load_failed_list(&failed_list_path)?, &destinations)
+            .into_iter()
+            .map(|entry| (crate::retry_key(&entry), entry))
+            .collect();
+    let mut dead_letters = crate::per_destination(state::load_failed_list(&dead_letter_path)?, &destinations);
+
+    let name = SinkKind::AlcApi.name();
+    let to_upload: Vec<&ChangedFile> = findings
+        .iter()
+        .filter(|f| match f.status {
+            Status::Missing => args.upload_missing,
+            Status::Differs => args.upload_differing,
+            Status::RemoteOnly => false,
+        })
+        .filter_map(|f| f.local)
+        .filter(|file| {
+            let dead = dead_letters
+                .iter()
+                .any(|e| e.path == file.path && e.destination.as_deref() == Some(name));
+            if dead {
+                info!("Skipping {}: in the dead-letter list", file.path.display());
+            }
+            !dead
+        })
+        .collect();
+    if config.dry_run {
+        if !to_upload.is_empty() {
+            info!("Dry run mode: skipping uploads");
+            for file in &to_upload {
+                info!("  Would upload: {}", file.path.display());
+            }
+        }
+        return Ok(());
+    }
+    if !to_upload.is_empty() && !throttle.is_open() {
+        warn!("Outside the upload windows: not uploading {} file(s)", to_upload.len());
+    } else if !to_upload.is_empty() {
+        let retry_policy = retry::RetryPolicy::from_config(config);
+        let total = to_upload.len();
+        let alc_api = &alc_api;
+        let throttle = &throttle;
+        let transforms = &transforms;
+        let mut outcomes = futures_util::stream::iter(to_upload.into_iter().enumerate())
+            .map(|(i, file)| async move {
+                info!("Uploading {}/{}: {}", i + 1, total, file.path.display());
+                let label = format!("[{}/{}] {}", i + 1, total, file.path.display());
+                let result = if throttle.is_open() {
+                    match transforms.prepare(file).await {
+                        Ok(prepared) => {
+                            retry_policy
+                                .run(&label, || async {
+                                    let id = alc_api.upload(&prepared).await?;
+                                    if config.verify {
+                                        sink::verify(alc_api, name, id.as_deref(), &prepared).await?;
+                                    }
+                                    Ok(id)
+                                })
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    Err(throttle::OutsideWindow.into())
+                };
+                (i, file, result)
+            })
+            .buffer_unordered(config.concurrency as usize);
+
+        let mut failures: Vec<(PathBuf, String, String)> = Vec::new();
+        let (mut uploaded, mut failed, mut transform_failed) = (0usize, 0usize, 0usize);
+        while let Some((i, file, result)) = outcomes.next().await {
+            match result {
+                Ok(id) => {
+                    uploaded += 1;
+                    retries.remove(&(file.path.clone(), name.to_string()));
+                    if let Some(ids) = stored_ids(&mut manifest, file) {
+                        match id {
+                            Some(id) => ids.insert(name.to_string(), id),
+                            None => ids.remove(name),
+                        };
+                    }
+                }
+                Err(e) if e.downcast_ref::<throttle::OutsideWindow>().is_some() => {
+                    info!("[{}/{}] Not uploaded, the upload window has closed: {}", i + 1, total, file.path.display());
+                }
+                Err(e) if e.downcast_ref::<uploader::FileUnstable>().is_some() => {
+                    info!("[{}/{}] Skipped: {:#}", i + 1, total, e);
+                }
+                Err(e) => {
+                    if e.downcast_ref::<transform::TransformFailed>().is_some() {
+                        warn!("[{}/{}] {}", i + 1, total, e);
+                        transform_failed += 1;
+                    } else {
+                        warn!("[{}/{}] Failed: {}: {:#}", i + 1, total, file.path.display(), e);
+                        failed += 1;
+                    }
+                    // Recorded like a failure of a regular run, so the next run retries it
+                    failures.push((file.path.clone(), name.to_string(), format!("{:#}", e)));
+                }
+            }
+        }
+        drop(outcomes);
+        alc_api.finish()?;
+
+        info!(
+            "Reconcile: uploaded {} file(s), {} failed, {} not uploaded because a transform failed",
+            uploaded, failed, transform_failed
+        );
+        let retry_list = crate::record_failures(config, retries, &mut dead_letters, failures);
+        state::save_failed_list(&failed_list_path, &retry_list)?;
+        state::save_failed_list(&dead_letter_path, &dead_letters)?;
+    }
+
+    // Without a manifest the next regular run seeds one from the watermark; saving only the
+    // reconciled files would stop it and have every other file uploaded again
+    if has_manifest {
+        state::save_manifest(&manifest_path, &manifest)?;
+    } else {
+        info!("No manifest yet: leaving it to the next run to create");
+    }
+    Ok(())
+}
+
+/// Ids of the stored copies of `file`'s content, adding a manifest entry if there is none.
+/// An entry for other content is left for the next run to detect as a change.
+fn stored_ids<'a>(manifest: &'a mut state::Manifest, file: &ChangedFile) -> Option<&'a mut BTreeMap<String, String>> {
+    let entry = manifest
+        .files
+        .entry(file.rel_path.clone())
+        .or_insert_with(|| state::ManifestEntry { ids: Default::default(), ..file.entry.clone() });
+    (entry.sha256 == file.entry.sha256).then_some(&mut entry.ids)
+}
+
+/// Record a server copy with the local content, keeping an id the manifest already has.
+fn record_match(manifest: &mut state::Manifest, file: &ChangedFile, copy: &RemoteFile) {
+    if let Some(ids) = stored_ids(manifest, file) {
+        ids.entry(SinkKind::AlcApi.name().to_string()).or_insert_with(|| copy.uuid.clone());
+    }
+}
+
+/// Whether a server copy has the local content. Copies without a recorded hash are compared
+/// by size only.
+fn same_content(local: &ChangedFile, remote: &RemoteFile) -> bool {
+    match &remote.sha256 {
+        Some(sha256) => sha256.eq_ignore_ascii_case(&local.entry.sha256),
+        None => remote.size == Some(local.entry.size),
+    }
+}
+
+/// Write the findings as tab-separated lines:
+/// status, path, local size, local SHA-256, then the server copies' uuids, sizes and hashes
+/// (comma-separated when the server holds several copies under the path).
+fn write_report(path: &Path, findings: &[Finding]) -> Result<()> {
+    let mut content = String::from("status\tpath\tlocal_size\tlocal_sha256\tremote_uuid\tremote_size\tremote_sha256\n");
+    for finding in findings {
+        let remote = |field: fn(&RemoteFile) -> String| {
+            finding.remote.iter().map(|r| field(r)).collect::<Vec<_>>().join(",")
+        };
+        content.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            finding.status.name(),
+            finding.rel_path,
+            finding.local.map(|f| f.entry.size.to_string()).unwrap_or_default(),
+            finding.local.map(|f| f.entry.sha256.as_str()).unwrap_or_default(),
+            remote(|r| r.uuid.clone()),
+            remote(|r| r.size.map(|n| n.to_string()).unwrap_or_default()),
+            remote(|r| r.sha256.clone().unwrap_or_default()),
+        ));
+    }
+    std::fs::write(path, content).with_context(|| format!("Writing reconcile report {}", path.display()))?;
+    info!("Reconcile report written to {}", path.display());
+    Ok(())
+}
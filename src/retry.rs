@@ -0,0 +1,119 @@
+use anyhow::Result;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::cli::Config;
+use crate::uploader::{FileUnstable, HttpError};
+
+/// Delay before the first retry; doubled for each further attempt.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for a single backoff delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often and for how long a transient failure is retried within one run.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts per operation, including the first.
+    pub max_attempts: u32,
+    /// Total time spent waiting between attempts of one operation.
+    pub max_total_delay: Duration,
+}
+
+enum ErrorClass {
+    /// Retrying may help; the server may have said how long to wait.
+    Transient(Option<Duration>),
+    /// Retrying will not help (rejected request, local error, file still changing).
+    Permanent,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        RetryPolicy {
+            max_attempts: config.max_attempts,
+            max_total_delay: Duration::from_secs(config.max_retry_delay_secs),
+        }
+    }
+
+    /// Run `op`, retrying transient errors with exponential backoff and jitter, or after
+    /// the server's `Retry-After`. Permanent errors are returned immediately.
+    pub async fn run<T, F, Fut>(&self, label: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut waited = Duration::ZERO;
+        let mut attempt = 1;
+        loop {
+            let err = match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            let requested = match classify(&err) {
+                ErrorClass::Transient(requested) => requested,
+                ErrorClass::Permanent => return Err(err),
+            };
+            if attempt >= self.max_attempts {
+                return Err(err.context(format!("Giving up after {} attempt(s)", attempt)));
+            }
+            let delay = requested.unwrap_or_else(|| backoff(attempt));
+            if waited + delay > self.max_total_delay {
+                return Err(err.context(format!(
+                    "Giving up after {} attempt(s): next retry in {}s would exceed the {}s retry budget",
+                    attempt,
+                    delay.as_secs(),
+                    self.max_total_delay.as_secs()
+                )));
+            }
+            warn!(
+                "{}: attempt {}/{} failed, retrying in {:.1}s: {:#}",
+                label,
+                attempt,
+                self.max_attempts,
+                delay.as_secs_f64(),
+                err
+            );
+            sleep(delay).await;
+            waited += delay;
+            attempt += 1;
+        }
+    }
+}
+
+/// Network errors, timeouts, 408, 429 and 5xx are transient; other HTTP errors
+/// (400, 413, 415, ...) and local errors are not.
+fn classify(err: &anyhow::Error) -> ErrorClass {
+    if err.downcast_ref::<FileUnstable>().is_some() {
+        return ErrorClass::Permanent;
+    }
+    if let Some(http) = err.downcast_ref::<HttpError>() {
+        let status = http.status.as_u16();
+        return if status == 408 || status == 429 || http.status.is_server_error() {
+            ErrorClass::Transient(http.retry_after)
+        } else {
+            ErrorClass::Permanent
+        };
+    }
+    match err.chain().find_map(|e| e.downcast_ref::<reqwest::Error>()) {
+        Some(e) if !e.is_builder() => ErrorClass::Transient(None),
+        _ => ErrorClass::Permanent,
+    }
+}
+
+/// Exponential backoff with jitter: a random delay between half and all of
+/// `BASE_DELAY * 2^(attempt - 1)`, capped at `MAX_BACKOFF`.
+fn backoff(attempt: u32) -> Duration {
+    let full = BASE_DELAY
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_BACKOFF);
+    full.mul_f64(0.5 + 0.5 * random_fraction())
+}
+
+/// A random value in [0, 1) from the standard library's randomly seeded hasher.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().hash_one(SystemTime::now());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
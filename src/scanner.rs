@@ -1,43 +1,542 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use tracing::{info, warn};
-use walkdir::WalkDir;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
 
-pub fn find_changed_files(root: &Path, since: SystemTime) -> Result<Vec<PathBuf>> {
-    let mut changed = Vec::new();
+use crate::cli::{Config, HiddenPolicy, LinkPolicy};
+use crate::filter::PathFilter;
+use crate::state::{DirCache, Manifest, ManifestEntry};
+use crate::walk::{self, DirListings, WalkedFile};
 
-    for entry in WalkDir::new(root).follow_links(false) {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(e) => {
-                warn!("Skipping unreadable entry: {}", e);
-                continue;
+/// How far a file's timestamps may be ahead of this machine's clock and still be taken
+/// as a recent write on a share whose clock runs ahead.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(15 * 60);
+
+/// Which files the scanner considers, derived from the run configuration.
+pub struct ScanOptions {
+    pub filter: PathFilter,
+    pub links: LinkPolicy,
+    pub hidden: HiddenPolicy,
+    /// Files written to more recently than this are deferred to a later run.
+    pub quiet_period: Duration,
+    /// Threads listing directories and hashing files concurrently.
+    pub workers: usize,
+    /// How often an incremental scan lists every directory regardless of the cache.
+    pub full_rescan_interval: Duration,
+    /// Directory levels below the root to descend into; files directly in the root are depth 1.
+    pub max_depth: Option<usize>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub skip_empty: bool,
+    /// Only files modified before this time are considered.
+    pub older_than: Option<SystemTime>,
+    /// Only files modified after this time are considered.
+    pub newer_than: Option<SystemTime>,
+    /// Set to stop a running scan, which then fails.
+    pub cancelled: Arc<AtomicBool>,
+}
+
+impl ScanOptions {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let now = SystemTime::now();
+        Ok(ScanOptions {
+            filter: PathFilter::new(&config.include, &config.exclude, !config.no_default_excludes)?,
+            links: config.links,
+            hidden: config.hidden,
+            quiet_period: Duration::from_secs(config.quiet_period_secs),
+            workers: config.scan_workers as usize,
+            full_rescan_interval: Duration::from_secs(config.full_rescan_hours.saturating_mul(3600)),
+            max_depth: config.max_depth,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            skip_empty: config.skip_empty,
+            older_than: config.older_than.map(|t| t.resolve(now)),
+            newer_than: config.newer_than.map(|t| t.resolve(now)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Fails once the scan has been cancelled.
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            anyhow::bail!("Scan stopped");
+        }
+        Ok(())
+    }
+
+    /// Why a file is outside the configured depth, size and age limits, if it is.
+    pub fn skip_reason(&self, rel_path: &str, size: u64, mtime: SystemTime) -> Option<String> {
+        let depth = rel_path.split('/').count();
+        if let Some(max) = self.max_depth.filter(|max| depth > *max) {
+            return Some(format!("depth {} exceeds --max-depth {}", depth, max));
+        }
+        if self.skip_empty && size == 0 {
+            return Some("empty file".to_string());
+        }
+        if let Some(min) = self.min_size.filter(|min| size < *min) {
+            return Some(format!("size {} bytes is below --min-size {}", size, min));
+        }
+        if let Some(max) = self.max_size.filter(|max| size > *max) {
+            return Some(format!("size {} bytes exceeds --max-size {}", size, max));
+        }
+        if let Some(cutoff) = self.older_than.filter(|cutoff| mtime >= *cutoff) {
+            return Some(format!(
+                "modified {} is not older than --older-than {}",
+                DateTime::<Utc>::from(mtime).to_rfc3339(),
+                DateTime::<Utc>::from(cutoff).to_rfc3339()
+            ));
+        }
+        if let Some(cutoff) = self.newer_than.filter(|cutoff| mtime <= *cutoff) {
+            return Some(format!(
+                "modified {} is not newer than --newer-than {}",
+                DateTime::<Utc>::from(mtime).to_rfc3339(),
+                DateTime::<Utc>::from(cutoff).to_rfc3339()
+            ));
+        }
+        None
+    }
+}
+
+/// A file whose content differs from the manifest and needs uploading.
+#[derive(Debug, Clone)]
+pub struct ChangedFile {
+    pub path: PathBuf,
+    /// Path relative to the scan root, used as the manifest key.
+    pub rel_path: String,
+    pub entry: ManifestEntry,
+    /// Creation time of the scanned file, where the file system records one.
+    pub ctime: Option<DateTime<Utc>>,
+    /// For `--transform` output, the relative path of the scanned file it was made from.
+    pub origin: Option<String>,
+}
+
+impl ChangedFile {
+    /// Relative path of the scanned file behind this upload, which stays the same across runs
+    /// whatever the transforms name their output.
+    pub fn source_rel_path(&self) -> &str {
+        self.origin.as_deref().unwrap_or(&self.rel_path)
+    }
+}
+
+/// Outcome of a scan.
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub changed: Vec<ChangedFile>,
+    /// Files skipped because they are still being written.
+    pub deferred: usize,
+    /// Manifest entries whose file no longer exists, keyed by relative path.
+    pub vanished: Vec<(String, ManifestEntry)>,
+    /// Entries that could not be read. Deletion detection is skipped when this is non-zero.
+    pub errors: usize,
+    /// Highest mtime among the files stated, i.e. the latest time seen on the share's clock.
+    /// Mtimes more than `MAX_CLOCK_SKEW` ahead of this machine's clock are left out.
+    pub max_mtime: Option<SystemTime>,
+}
+
+/// Walk `root` and return files whose content differs from `manifest`.
+///
+/// Files whose size and mtime match the manifest and whose mtime is not newer than `since`
+/// are trusted without hashing. Everything else is hashed, so a file copied in with an old
+/// mtime is still detected and a file that was merely touched is not re-uploaded (its new
+/// mtime is recorded in the manifest instead).
+///
+/// When `seed` is set (no manifest existed yet), files not newer than `since` are recorded
+/// in the manifest as already uploaded rather than reported as changed.
+///
+/// With a `dir_cache` (incremental mode), directories whose mtime is unchanged are not listed
+/// again and their files are assumed unchanged. A directory's mtime only changes when entries
+/// are added, removed or renamed, so in-place edits are picked up by the periodic full rescan.
+pub fn find_changed_files(
+    root: &Path,
+    options: &ScanOptions,
+    since: SystemTime,
+    manifest: &mut Manifest,
+    seed: bool,
+    dir_cache: Option<&mut DirCache>,
+) -> Result<ScanResult> {
+    if !root.is_dir() {
+        anyhow::bail!("Scan root {} is not a readable directory", root.display());
+    }
+
+    let mut result = ScanResult::default();
+    let now = Utc::now();
+    let mut dir_cache = dir_cache;
+    let walked = match dir_cache.as_deref_mut() {
+        None => walk::walk(root, options, DirListings::Ignore),
+        Some(cache) => {
+            let full_due = match cache.last_full_scan {
+                Some(t) => (now - t).to_std().map_or(true, |age| age >= options.full_rescan_interval),
+                None => true,
+            };
+            let mut walked = if full_due {
+                info!("Incremental scan: doing a full rescan");
+                walk::walk(root, options, DirListings::Record)
+            } else {
+                walk::walk(root, options, DirListings::Reuse(&cache.dirs))
+            };
+            if full_due && walked.errors == 0 {
+                cache.last_full_scan = Some(now);
             }
-        };
+            cache.dirs = std::mem::take(&mut walked.dirs);
+            walked
+        }
+    };
+    options.check_cancelled()?;
+    result.errors = walked.errors;
+
+    let seen: HashSet<String> = walked
+        .files
+        .iter()
+        .map(|f| f.rel_path.clone())
+        .chain(walked.cached_files.iter().cloned())
+        .collect();
 
-        if !entry.file_type().is_file() {
+    // Directories holding files that were deferred or could not be hashed. Finishing a write
+    // does not change the directory's mtime, so their listings must not be reused.
+    let mut unsettled_dirs: HashSet<String> = HashSet::new();
+
+    // Decide cheaply which files need hashing
+    let mut to_hash: Vec<(WalkedFile, SystemTime)> = Vec::new();
+    for file in walked.files {
+        let deferred = result.deferred;
+        let Some(mtime) = admit(&file, options, &mut result) else {
+            if result.deferred > deferred {
+                unsettled_dirs.insert(parent_rel(&file.rel_path).to_string());
+            }
             continue;
+        };
+
+        if let Some(known) = manifest.files.get(&file.rel_path) {
+            if known.size == file.meta.len() && known.mtime == DateTime::<Utc>::from(mtime) && mtime <= since {
+                continue;
+            }
         }
+        to_hash.push((file, mtime));
+    }
 
-        let mtime = match entry.metadata().ok().and_then(|m| m.modified().ok()) {
-            Some(t) => t,
-            None => {
-                warn!("Cannot read mtime for {}", entry.path().display());
+    let hashes = hash_files(&to_hash, options);
+    options.check_cancelled()?;
+
+    for ((file, mtime), sha256) in to_hash.into_iter().zip(hashes) {
+        let sha256 = match sha256 {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Cannot hash {}: {:#}", file.path.display(), e);
+                result.errors += 1;
+                unsettled_dirs.insert(parent_rel(&file.rel_path).to_string());
                 continue;
             }
         };
+        let current = ManifestEntry {
+            size: file.meta.len(),
+            mtime: mtime.into(),
+            sha256,
+            ids: Default::default(),
+        };
 
-        if mtime > since {
-            info!(
-                "Changed: {} (mtime: {:?})",
-                entry.path().display(),
-                mtime
+        match manifest.files.get(&file.rel_path) {
+            Some(known) if known.sha256 == current.sha256 => {
+                if known.mtime != current.mtime {
+                    debug!("Touched but unchanged: {}", file.path.display());
+                    let ids = known.ids.clone();
+                    manifest.files.insert(file.rel_path, ManifestEntry { ids, ..current });
+                }
+            }
+            None if seed && mtime <= since => {
+                debug!("Seeding manifest: {}", file.path.display());
+                manifest.files.insert(file.rel_path, current);
+            }
+            _ => {
+                info!(
+                    "Changed: {} (mtime: {:?})",
+                    file.path.display(),
+                    mtime
+                );
+                result.changed.push(ChangedFile {
+                    ctime: file.meta.created().ok().map(Into::into),
+                    path: file.path,
+                    rel_path: file.rel_path,
+                    entry: current,
+                    origin: None,
+                });
+            }
+        }
+    }
+
+    result.changed.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if let Some(cache) = dir_cache {
+        for dir in &unsettled_dirs {
+            debug!("Not caching the listing of {:?}: it has files to check again", dir);
+            cache.dirs.remove(dir);
+        }
+    }
+
+    if result.errors > 0 {
+        warn!("Skipping deletion detection: {} entries could not be read", result.errors);
+    } else {
+        // Files in excluded directories were never walked, so confirm they are really gone
+        result.vanished = manifest
+            .files
+            .iter()
+            .filter(|(rel, _)| !seen.contains(*rel) && !root.join(rel).exists())
+            .map(|(rel, entry)| (rel.clone(), entry.clone()))
+            .collect();
+    }
+
+    Ok(result)
+}
+
+/// Walk `root` and hash every file the filters and limits admit, regardless of the manifest
+/// and watermark. `changed` holds all of them; files still being written are only counted.
+pub fn hash_tree(root: &Path, options: &ScanOptions) -> Result<ScanResult> {
+    if !root.is_dir() {
+        anyhow::bail!("Scan root {} is not a readable directory", root.display());
+    }
+
+    let walked = walk::walk(root, options, DirListings::Ignore);
+    options.check_cancelled()?;
+    let mut result = ScanResult {
+        errors: walked.errors,
+        ..Default::default()
+    };
+    let mut to_hash: Vec<(WalkedFile, SystemTime)> = Vec::new();
+    for file in walked.files {
+        if let Some(mtime) = admit(&file, options, &mut result) {
+            to_hash.push((file, mtime));
+        }
+    }
+
+    let hashes = hash_files(&to_hash, options);
+    options.check_cancelled()?;
+    for ((file, mtime), sha256) in to_hash.into_iter().zip(hashes) {
+        match sha256 {
+            Ok(sha256) => result.changed.push(ChangedFile {
+                ctime: file.meta.created().ok().map(Into::into),
+                path: file.path,
+                rel_path: file.rel_path,
+                entry: ManifestEntry {
+                    size: file.meta.len(),
+                    mtime: mtime.into(),
+                    sha256,
+                    ids: Default::default(),
+                },
+                origin: None,
+            }),
+            Err(e) => {
+                warn!("Cannot hash {}: {:#}", file.path.display(), e);
+                result.errors += 1;
+            }
+        }
+    }
+    result.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+/// Relative path of the directory holding a file (`""` for the scan root).
+pub fn parent_rel(rel_path: &str) -> &str {
+    rel_path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// The mtime of a walked file if it passes the limits and is not still being written.
+/// Skipped and deferred files are logged, and deferred ones counted in `result`.
+fn admit(file: &WalkedFile, options: &ScanOptions, result: &mut ScanResult) -> Option<SystemTime> {
+    let mtime = match file.meta.modified() {
+        Ok(t) => t,
+        Err(_) => {
+            warn!("Cannot read mtime for {}", file.path.display());
+            return None;
+        }
+    };
+    // A time further ahead than the share's clock can be would hold the watermark there
+    if mtime <= SystemTime::now() + MAX_CLOCK_SKEW {
+        result.max_mtime = result.max_mtime.max(Some(mtime));
+    } else {
+        debug!("Not using the mtime of {} for the watermark: it is in the future", file.path.display());
+    }
+
+    if let Some(reason) = options.skip_reason(&file.rel_path, file.meta.len(), mtime) {
+        info!("Skipping {}: {}", file.path.display(), reason);
+        return None;
+    }
+
+    if is_settling(&file.path, &file.meta, options.quiet_period) {
+        info!("Deferring {}: modified within the last {}s", file.path.display(), options.quiet_period.as_secs());
+        result.deferred += 1;
+        return None;
+    }
+    Some(mtime)
+}
+
+/// The file `probe_server_time` creates and removes in the scan root.
+pub fn probe_path(root: &Path) -> PathBuf {
+    root.join("~smb-watch-clock-probe.tmp")
+}
+
+/// Outcome of checking a single file outside a scan.
+pub enum FileCheck {
+    Ready(ChangedFile),
+    /// Excluded by the filters or limits.
+    Skipped,
+    /// Still being written.
+    Deferred,
+}
+
+/// Stat and hash a single file, e.g. a retry candidate that the scan did not report.
+pub fn describe_file(root: &Path, path: &Path, options: &ScanOptions) -> Result<FileCheck> {
+    let rel_path = relative_path(root, path);
+    if !options.filter.matches_file(&rel_path) {
+        info!("Skipping {}: excluded by filters", path.display());
+        return Ok(FileCheck::Skipped);
+    }
+
+    let meta = std::fs::metadata(path)
+        .with_context(|| format!("Reading metadata for {}", path.display()))?;
+    if options.hidden == HiddenPolicy::Ignore
+        && path.file_name().is_some_and(|name| walk::is_hidden(name, &meta))
+    {
+        info!("Skipping {}: hidden file", path.display());
+        return Ok(FileCheck::Skipped);
+    }
+    let mtime = meta
+        .modified()
+        .with_context(|| format!("Reading mtime for {}", path.display()))?;
+
+    if let Some(reason) = options.skip_reason(&rel_path, meta.len(), mtime) {
+        info!("Skipping {}: {}", path.display(), reason);
+        return Ok(FileCheck::Skipped);
+    }
+
+    if is_settling(path, &meta, options.quiet_period) {
+        info!("Deferring {}: modified within the last {}s", path.display(), options.quiet_period.as_secs());
+        return Ok(FileCheck::Deferred);
+    }
+
+    Ok(FileCheck::Ready(ChangedFile {
+        path: path.to_path_buf(),
+        rel_path,
+        entry: ManifestEntry {
+            size: meta.len(),
+            mtime: mtime.into(),
+            sha256: hash_file(path)?,
+            ids: Default::default(),
+        },
+        ctime: meta.created().ok().map(Into::into),
+        origin: None,
+    }))
+}
+
+/// Whether a file was written to within the quiet period.
+///
+/// The creation time is considered too, because Explorer keeps the original mtime when it
+/// copies a file but the copy's creation time is when the copy started.
+fn is_settling(path: &Path, meta: &std::fs::Metadata, quiet_period: Duration) -> bool {
+    if quiet_period.is_zero() {
+        return false;
+    }
+    let last_activity = match (meta.modified(), meta.created()) {
+        (Ok(m), Ok(c)) => m.max(c),
+        (Ok(m), Err(_)) => m,
+        _ => return false,
+    };
+    match SystemTime::now().duration_since(last_activity) {
+        Ok(age) => age < quiet_period,
+        // A timestamp slightly in the future (server clock ahead) also counts as recent;
+        // one further ahead is wrong and would defer the file forever
+        Err(e) if e.duration() <= MAX_CLOCK_SKEW => true,
+        Err(e) => {
+            warn!(
+                "{} has a timestamp {}s in the future; not waiting for it to settle",
+                path.display(),
+                e.duration().as_secs()
             );
-            changed.push(entry.path().to_path_buf());
+            false
+        }
+    }
+}
+
+/// Read the share's current time by creating a probe file in `root` and reading its mtime.
+/// The name matches the built-in `*.tmp` exclude in case a crash leaves it behind.
+pub fn probe_server_time(root: &Path) -> Result<SystemTime> {
+    let probe = probe_path(root);
+    File::create(&probe).with_context(|| format!("Creating probe file {}", probe.display()))?;
+    let mtime = std::fs::metadata(&probe)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("Reading mtime of probe file {}", probe.display()));
+    if let Err(e) = std::fs::remove_file(&probe) {
+        warn!("Failed to remove probe file {}: {}", probe.display(), e);
+    }
+    mtime
+}
+
+/// Hash files on `options.workers` threads, returning results in input order. Files left
+/// when the scan is cancelled are not hashed.
+fn hash_files(files: &[(WalkedFile, SystemTime)], options: &ScanOptions) -> Vec<Result<String>> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<String>>>> =
+        Mutex::new((0..files.len()).map(|_| None).collect());
+
+    thread::scope(|s| {
+        for _ in 0..options.workers.max(1).min(files.len()) {
+            s.spawn(|| loop {
+                if options.cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((file, _)) = files.get(i) else { break };
+                let hash = hash_file_unless(&file.path, &options.cancelled);
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(hash);
+            });
         }
+    });
+
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(anyhow::anyhow!("File was not hashed"))))
+        .collect()
+}
+
+/// SHA-256 of a file's content as lowercase hex.
+pub fn hash_file(path: &Path) -> Result<String> {
+    hash_file_unless(path, &AtomicBool::new(false))
+}
+
+/// Like `hash_file`, but gives up once `cancelled` is set, so a cancelled scan does not wait
+/// for a large file to be read to the end.
+fn hash_file_unless(path: &Path, cancelled: &AtomicBool) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut reader = BufReader::with_capacity(64 * 1024, file);
+    let mut hasher = Sha256::new();
+    loop {
+        let chunk = reader.fill_buf().with_context(|| format!("Reading {}", path.display()))?;
+        if chunk.is_empty() {
+            break;
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            anyhow::bail!("Scan stopped");
+        }
+        hasher.update(chunk);
+        let len = chunk.len();
+        reader.consume(len);
     }
+    Ok(hex::encode(hasher.finalize()))
+}
 
-    changed.sort();
-    Ok(changed)
+/// Path of `path` relative to `root` with `/` separators, falling back to the full path.
+pub fn relative_path(root: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
 }
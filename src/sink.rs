@@ -0,0 +1,131 @@
+mod alc_api;
+mod local_dir;
+mod s3;
+mod webdav;
+
+pub use alc_api::AlcApiSink;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use crate::cli::{Config, SinkKind};
+use crate::scanner::ChangedFile;
+use crate::throttle::Throttle;
+
+/// A destination for changed files.
+///
+/// Each stored copy is identified by the id `upload` returns: a server-assigned uuid, or an
+/// object key or path. The id is recorded in the manifest and passed back for renames and
+/// deletions. Errors follow the uploader's conventions (`FileUnstable` defers the file,
+/// `HttpError` and network errors are classified for retries).
+#[async_trait]
+pub trait UploadSink: Send + Sync {
+    /// Upload a new or changed file. Returns the id of the stored copy, if known.
+    async fn upload(&self, file: &ChangedFile) -> Result<Option<String>>;
+
+    /// Follow a local rename. Returns the stored copy's id afterwards.
+    async fn rename(&self, id: &str, new_rel_path: &str) -> Result<String>;
+
+    /// Delete the stored copy of a file removed from the share.
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    /// Archive the stored copy of a file removed from the share.
+    async fn archive(&self, id: &str) -> Result<()>;
+
+    /// Size and hash of a stored copy, as read back from the destination for `--verify`.
+    async fn stored(&self, id: &str) -> Result<StoredCopy>;
+
+    /// Called once after the run's uploads, renames and deletions.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a destination holds for an uploaded file.
+#[derive(Debug)]
+pub struct StoredCopy {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl StoredCopy {
+    /// Size and hash of a downloaded copy, computed while streaming the response body.
+    pub async fn read(response: reqwest::Response) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Downloading stored copy")?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+        Ok(StoredCopy { size, sha256: hex::encode(hasher.finalize()) })
+    }
+}
+
+/// The stored copy read back with `--verify` differs from the uploaded file.
+#[derive(Debug)]
+pub struct VerifyMismatch {
+    pub id: String,
+    pub expected: StoredCopy,
+    pub stored: StoredCopy,
+}
+
+impl std::fmt::Display for VerifyMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stored copy {} does not match: {} bytes, SHA-256 {} (expected {} bytes, SHA-256 {})",
+            self.id, self.stored.size, self.stored.sha256, self.expected.size, self.expected.sha256
+        )
+    }
+}
+
+impl std::error::Error for VerifyMismatch {}
+
+/// Check the copy just uploaded as `id` against the scanned size and hash. A copy that does
+/// not match is removed, so the next attempt uploads it again instead of being told by the
+/// destination that it already holds the file.
+pub async fn verify(sink: &dyn UploadSink, name: &str, id: Option<&str>, file: &ChangedFile) -> Result<()> {
+    let id = id.with_context(|| format!("{} returned no id for the stored copy, so it cannot be verified", name))?;
+    let stored = sink
+        .stored(id)
+        .await
+        .with_context(|| format!("Reading back {} from {} for verification", id, name))?;
+    if stored.size == file.entry.size && stored.sha256.eq_ignore_ascii_case(&file.entry.sha256) {
+        info!("Verified {} on {} ({})", file.path.display(), name, id);
+        return Ok(());
+    }
+    if let Err(e) = sink.delete(id).await {
+        warn!("Could not remove mismatched copy {} from {}: {:#}", id, name, e);
+    }
+    Err(VerifyMismatch {
+        id: id.to_string(),
+        expected: StoredCopy { size: file.entry.size, sha256: file.entry.sha256.clone() },
+        stored,
+    }
+    .into())
+}
+
+/// Create a sink selected with `--sink`, signing in where the destination needs it.
+pub async fn connect(
+    kind: SinkKind,
+    config: &Config,
+    scan_root: &Path,
+    client: &reqwest::Client,
+    throttle: &Arc<Throttle>,
+) -> Result<Box<dyn UploadSink>> {
+    Ok(match kind {
+        SinkKind::AlcApi => Box::new(
+            alc_api::AlcApiSink::connect(config, scan_root, client.clone(), throttle.clone()).await?,
+        ),
+        SinkKind::LocalDir => Box::new(local_dir::LocalDirSink::new(config, throttle.clone())?),
+        SinkKind::S3 => Box::new(s3::S3Sink::new(config, client.clone(), throttle.clone())?),
+        SinkKind::Webdav => Box::new(webdav::WebDavSink::new(config, client.clone(), throttle.clone())?),
+    })
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+use super::{StoredCopy, UploadSink};
+use crate::cli::{Config, UploadMode};
+use crate::scanner::ChangedFile;
+use crate::throttle::Throttle;
+use crate::uploader::{self, MetadataOptions, RemoteFile, SessionStore};
+use crate::{auth, google_auth, state};
+
+/// rust-alc-api's `/api/files` endpoint. Stored copies are identified by server-assigned uuids.
+pub struct AlcApiSink {
+    client: reqwest::Client,
+    files_url: String,
+    token: String,
+    upload_mode: UploadMode,
+    chunk_threshold: Option<u64>,
+    preflight_check: bool,
+    metadata: MetadataOptions,
+    sessions: SessionStore,
+    scan_root: PathBuf,
+    throttle: Arc<Throttle>,
+}
+
+impl AlcApiSink {
+    pub async fn connect(
+        config: &Config,
+        scan_root: &Path,
+        client: reqwest::Client,
+        throttle: Arc<Throttle>,
+    ) -> Result<Self> {
+        // Google Device Flow → rust-alc-api で認証
+        let id_token = google_auth::device_flow_get_id_token(
+            &client,
+            &config.google_client_id,
+            &config.google_client_secret,
+        ).await?;
+
+        let base_url = config.alc_api_url.trim_end_matches('/');
+        let auth_url = format!("{}/api/auth/google", base_url);
+        let (token, tenant_id) = auth::login_with_google(&client, &auth_url, &id_token).await?;
+        info!("Authenticated: tenant_id={}", tenant_id);
+
+        Ok(AlcApiSink {
+            client,
+            files_url: format!("{}/api/files", base_url),
+            token,
+            upload_mode: config.upload_mode,
+            chunk_threshold: config.chunk_threshold,
+            preflight_check: config.preflight_check,
+            metadata: MetadataOptions::from_config(config),
+            sessions: SessionStore::load(state::upload_sessions_path(&config.state_file), config.chunk_size)?,
+            scan_root: scan_root.to_path_buf(),
+            throttle,
+        })
+    }
+
+    /// Every file the server holds for the tenant.
+    pub async fn list_files(&self) -> Result<Vec<RemoteFile>> {
+        uploader::list_files(&self.client, &self.files_url, &self.token).await
+    }
+}
+
+#[async_trait]
+impl UploadSink for AlcApiSink {
+    async fn upload(&self, file: &ChangedFile) -> Result<Option<String>> {
+        if self.preflight_check {
+            if let Some(uuid) = uploader::find_existing(&self.client, &self.files_url, file, &self.token).await {
+                return Ok(Some(uuid));
+            }
+        }
+        if self.chunk_threshold.is_some_and(|t| file.entry.size >= t) {
+            uploader::upload_file_chunked(
+                &self.client,
+                &self.files_url,
+                file,
+                &self.token,
+                &self.sessions,
+                &self.metadata,
+                &self.throttle,
+            )
+            .await
+        } else {
+            uploader::upload_file(
+                &self.client,
+                &self.files_url,
+                file,
+                &self.token,
+                self.upload_mode,
+                &self.metadata,
+                &self.throttle,
+            )
+            .await
+        }
+    }
+
+    async fn rename(&self, id: &str, new_rel_path: &str) -> Result<String> {
+        uploader::rename_file(&self.client, &self.files_url, id, new_rel_path, &self.token, &self.metadata).await?;
+        Ok(id.to_string())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        uploader::delete_file(&self.client, &self.files_url, id, &self.token).await
+    }
+
+    async fn archive(&self, id: &str) -> Result<()> {
+        uploader::archive_file(&self.client, &self.files_url, id, &self.token).await
+    }
+
+    async fn stored(&self, id: &str) -> Result<StoredCopy> {
+        uploader::stored_file(&self.client, &self.files_url, id, &self.token).await
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.sessions.retain(|rel| self.scan_root.join(rel).exists())
+    }
+}
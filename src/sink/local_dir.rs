@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+use super::{StoredCopy, UploadSink};
+use crate::cli::Config;
+use crate::scanner::{self, ChangedFile};
+use crate::throttle::{self, Throttle};
+use crate::uploader::{self, FileUnstable};
+
+/// Subdirectory of the mirror that archived files are moved into.
+const ARCHIVE_DIR: &str = ".archive";
+
+/// Mirrors the share into a directory. Stored copies are identified by their relative path.
+pub struct LocalDirSink {
+    root: PathBuf,
+    throttle: Arc<Throttle>,
+}
+
+impl LocalDirSink {
+    pub fn new(config: &Config, throttle: Arc<Throttle>) -> Result<Self> {
+        let root = config
+            .mirror_dir
+            .clone()
+            .context("--mirror-dir is required for --sink local-dir")?;
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Creating mirror directory {}", root.display()))?;
+        info!("Mirroring into {}", root.display());
+        Ok(LocalDirSink { root, throttle })
+    }
+
+    /// Copy up to `size` bytes, paced by the bandwidth limit since the mirror may be remote.
+    async fn copy(&self, from: &Path, to: &Path, size: u64) -> std::io::Result<u64> {
+        let reader = tokio::fs::File::open(from).await?.take(size);
+        let mut chunks = std::pin::pin!(throttle::throttled(ReaderStream::new(reader), self.throttle.clone()));
+        let mut writer = tokio::fs::File::create(to).await?;
+        let mut copied = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            writer.write_all(&chunk).await?;
+            copied += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(copied)
+    }
+}
+
+#[async_trait]
+impl UploadSink for LocalDirSink {
+    async fn upload(&self, file: &ChangedFile) -> Result<Option<String>> {
+        uploader::ensure_unchanged(file).await?;
+
+        // Copy next to the destination first, so the mirror never holds a partial file
+        let dest = self.root.join(&file.rel_path);
+        create_parent(&dest).await?;
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = dest.with_file_name(format!(".{}.smb-watch.tmp", name));
+        let copied = self
+            .copy(&file.path, &tmp, file.entry.size)
+            .await
+            .with_context(|| format!("Copying {} to {}", file.path.display(), tmp.display()))?;
+        if copied != file.entry.size || uploader::ensure_unchanged(file).await.is_err() {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(FileUnstable { path: file.path.clone() }.into());
+        }
+        tokio::fs::rename(&tmp, &dest)
+            .await
+            .with_context(|| format!("Replacing {}", dest.display()))?;
+
+        info!("Copied {} -> {}", file.path.display(), dest.display());
+        Ok(Some(file.rel_path.clone()))
+    }
+
+    async fn rename(&self, id: &str, new_rel_path: &str) -> Result<String> {
+        move_file(&self.root.join(id), &self.root.join(new_rel_path)).await?;
+        Ok(new_rel_path.to_string())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let path = self.root.join(id);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn archive(&self, id: &str) -> Result<()> {
+        move_file(&self.root.join(id), &self.root.join(ARCHIVE_DIR).join(id)).await
+    }
+
+    async fn stored(&self, id: &str) -> Result<StoredCopy> {
+        let path = self.root.join(id);
+        let size = tokio::fs::metadata(&path)
+            .await
+            .with_context(|| format!("Reading metadata for {}", path.display()))?
+            .len();
+        let sha256 = tokio::task::spawn_blocking(move || scanner::hash_file(&path)).await??;
+        Ok(StoredCopy { size, sha256 })
+    }
+}
+
+async fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Creating directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    create_parent(to).await?;
+    tokio::fs::rename(from, to)
+        .await
+        .with_context(|| format!("Moving {} to {}", from.display(), to.display()))
+}
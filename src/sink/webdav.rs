@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+use super::{StoredCopy, UploadSink};
+use crate::cli::Config;
+use crate::content_type::ContentTypes;
+use crate::scanner::ChangedFile;
+use crate::throttle::Throttle;
+use crate::uploader::{self, FileUnstable};
+
+/// Collection (below `--webdav-url`) that archived files are moved into.
+const ARCHIVE_DIR: &str = ".archive";
+
+/// Characters left unescaped in path segments: RFC 3986 unreserved characters.
+const SEGMENT_ENCODE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// A WebDAV collection. Missing parent collections are created with MKCOL.
+/// Stored copies are identified by their relative path.
+pub struct WebDavSink {
+    client: reqwest::Client,
+    base_url: String,
+    user: Option<String>,
+    pass: Option<String>,
+    /// Collections known to exist, so MKCOL is sent once per directory and run.
+    collections: Mutex<HashSet<String>>,
+    throttle: Arc<Throttle>,
+    content_types: ContentTypes,
+}
+
+impl WebDavSink {
+    pub fn new(config: &Config, client: reqwest::Client, throttle: Arc<Throttle>) -> Result<Self> {
+        let base_url = config
+            .webdav_url
+            .clone()
+            .context("--webdav-url is required for --sink webdav")?
+            .trim_end_matches('/')
+            .to_string();
+        info!("Uploading to WebDAV collection {}", base_url);
+        Ok(WebDavSink {
+            client,
+            base_url,
+            user: config.webdav_user.clone(),
+            pass: config.webdav_pass.clone(),
+            collections: Mutex::new(HashSet::new()),
+            throttle,
+            content_types: ContentTypes::from_config(config),
+        })
+    }
+
+    fn url(&self, rel_path: &str) -> String {
+        let encoded: Vec<String> = rel_path
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, SEGMENT_ENCODE).to_string())
+            .collect();
+        format!("{}/{}", self.base_url, encoded.join("/"))
+    }
+
+    fn request(&self, method: Method, rel_path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, self.url(rel_path));
+        match &self.user {
+            Some(user) => request.basic_auth(user, self.pass.as_deref()),
+            None => request,
+        }
+    }
+
+    /// Create the collections leading up to `rel_path`, outermost first.
+    async fn create_parents(&self, rel_path: &str) -> Result<()> {
+        let segments: Vec<&str> = rel_path.split('/').collect();
+        let mut dir = String::new();
+        for segment in &segments[..segments.len() - 1] {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+            dir.push_str(segment);
+            if self.collections.lock().unwrap().contains(&dir) {
+                continue;
+            }
+            let mkcol = Method::from_bytes(b"MKCOL").expect("valid method");
+            let response = self
+                .request(mkcol, &dir)
+                .send()
+                .await
+                .with_context(|| format!("MKCOL {}", self.url(&dir)))?;
+            // 405: the collection already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                uploader::check_status(response, "Create collection").await?;
+                debug!("Created collection {}", dir);
+            }
+            self.collections.lock().unwrap().insert(dir.clone());
+        }
+        Ok(())
+    }
+
+    async fn move_to(&self, from: &str, to: &str) -> Result<()> {
+        self.create_parents(to).await?;
+        let response = self
+            .request(Method::from_bytes(b"MOVE").expect("valid method"), from)
+            .header("Destination", self.url(to))
+            .header("Overwrite", "T")
+            .send()
+            .await
+            .with_context(|| format!("MOVE {}", self.url(from)))?;
+        uploader::check_status(response, "Move").await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UploadSink for WebDavSink {
+    async fn upload(&self, file: &ChangedFile) -> Result<Option<String>> {
+        uploader::ensure_unchanged(file).await?;
+        self.create_parents(&file.rel_path).await?;
+
+        let mime = self.content_types.detect(&file.path).await?;
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        let body = uploader::file_body(&file.path, file.entry.size, hasher.clone(), self.throttle.clone()).await?;
+        let sent = self
+            .request(Method::PUT, &file.rel_path)
+            .header(CONTENT_TYPE, mime)
+            .header(CONTENT_LENGTH, file.entry.size)
+            .body(body)
+            .send()
+            .await;
+        let checked = match sent {
+            Ok(response) => uploader::check_status(response, "Upload").await.map(|_| ()),
+            Err(e) => Err(anyhow::Error::from(e).context(format!("PUT {}", self.url(&file.rel_path)))),
+        };
+        if let Err(e) = checked {
+            uploader::ensure_unchanged(file).await?;
+            return Err(e);
+        }
+
+        // The content was streamed, so a change during the upload only shows afterwards
+        let sent = hex::encode(std::mem::take(&mut *hasher.lock().unwrap()).finalize());
+        if sent != file.entry.sha256 || uploader::ensure_unchanged(file).await.is_err() {
+            return Err(FileUnstable { path: file.path.clone() }.into());
+        }
+
+        info!("Uploaded {} -> {}", file.path.display(), self.url(&file.rel_path));
+        Ok(Some(file.rel_path.clone()))
+    }
+
+    async fn rename(&self, id: &str, new_rel_path: &str) -> Result<String> {
+        self.move_to(id, new_rel_path).await?;
+        Ok(new_rel_path.to_string())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let response = self
+            .request(Method::DELETE, id)
+            .send()
+            .await
+            .with_context(|| format!("DELETE {}", self.url(id)))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        uploader::check_status(response, "Delete").await?;
+        Ok(())
+    }
+
+    async fn archive(&self, id: &str) -> Result<()> {
+        self.move_to(id, &format!("{}/{}", ARCHIVE_DIR, id)).await
+    }
+
+    async fn stored(&self, id: &str) -> Result<StoredCopy> {
+        let response = self
+            .request(Method::GET, id)
+            .send()
+            .await
+            .with_context(|| format!("GET {}", self.url(id)))?;
+        StoredCopy::read(uploader::check_status(response, "Download").await?).await
+    }
+}
@@ -1,12 +1,15 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use tracing::info;
 
-/// Read the last run timestamp from the last line of the state file.
+/// Read the scan watermark from the last line of the state file.
+/// Uses the watermark column when present, falling back to the run's start time.
 pub fn read_last_run(path: &Path) -> Result<SystemTime> {
     if !path.exists() {
         info!("No state file found at {}, will upload all files", path.display());
@@ -16,10 +19,7 @@ pub fn read_last_run(path: &Path) -> Result<SystemTime> {
     let content = std::fs::read_to_string(path)
         .with_context(|| format!("Reading state file {}", path.display()))?;
 
-    let last_line = content
-        .lines()
-        .filter(|l| !l.trim().is_empty())
-        .last();
+    let last_line = content.lines().rev().find(|l| !l.trim().is_empty());
 
     let last_line = match last_line {
         Some(l) => l,
@@ -29,14 +29,21 @@ pub fn read_last_run(path: &Path) -> Result<SystemTime> {
         }
     };
 
-    // First field is the start timestamp
-    let start_ts = last_line.split('\t').next().unwrap_or("").trim();
+    // First field is the start timestamp, eighth (if present) the watermark
+    let fields: Vec<&str> = last_line.split('\t').collect();
+    let ts = fields
+        .get(7)
+        .filter(|f| !f.trim().is_empty())
+        .or(fields.first())
+        .copied()
+        .unwrap_or("")
+        .trim();
 
-    let dt = DateTime::parse_from_rfc3339(start_ts)
-        .with_context(|| format!("Parsing last run timestamp: {:?}", start_ts))?;
+    let dt = DateTime::parse_from_rfc3339(ts)
+        .with_context(|| format!("Parsing last run timestamp: {:?}", ts))?;
 
     let time = SystemTime::from(dt);
-    info!("Last run: {}", dt.to_rfc3339());
+    info!("Last run watermark: {}", dt.to_rfc3339());
     Ok(time)
 }
 
@@ -46,25 +53,54 @@ pub struct RunRecord {
     pub files_found: usize,
     pub uploaded: usize,
     pub failed: usize,
+    /// Files not uploaded because a `--transform` step failed.
+    pub transform_failed: usize,
+    /// Files skipped because they changed while being uploaded.
+    pub deferred: usize,
+    /// Reference time for the next scan, taken from the share's clock where possible.
+    pub watermark: SystemTime,
     pub dry_run: bool,
+    /// Results for each configured destination, in `--sink` order.
+    pub destinations: Vec<DestinationResult>,
+}
+
+/// Uploads to one destination during a run.
+pub struct DestinationResult {
+    pub name: String,
+    pub uploaded: usize,
+    pub failed: usize,
+    pub transform_failed: usize,
 }
 
 /// Append a run record as a tab-separated line to the state file.
-/// Format: start_ts\tend_ts\tfiles_found\tuploaded\tfailed\tstatus
+/// Format: start_ts\tend_ts\tfiles_found\tuploaded\tfailed\tstatus\tdeferred\twatermark\tdestinations\ttransform_failed,
+/// where destinations lists `name=uploaded/failed` separated by commas.
 pub fn append_run_record(path: &Path, record: &RunRecord) -> Result<()> {
     let start_dt: DateTime<Utc> = record.start.into();
     let end_dt: DateTime<Utc> = record.end.into();
+    let watermark_dt: DateTime<Utc> = record.watermark.into();
 
     let status = if record.dry_run { "dry-run" } else { "ok" };
 
+    let destinations = record
+        .destinations
+        .iter()
+        .map(|d| format!("{}={}/{}", d.name, d.uploaded, d.failed))
+        .collect::<Vec<_>>()
+        .join(",");
+
     let line = format!(
-        "{}\t{}\t{}\t{}\t{}\t{}\n",
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
         start_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
         end_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
         record.files_found,
         record.uploaded,
         record.failed,
         status,
+        record.deferred,
+        watermark_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        destinations,
+        record.transform_failed,
     );
 
     if let Some(parent) = path.parent() {
@@ -84,13 +120,17 @@ pub fn append_run_record(path: &Path, record: &RunRecord) -> Result<()> {
         .with_context(|| format!("Writing to state file {}", path.display()))?;
 
     info!(
-        "Run recorded: start={} end={} found={} uploaded={} failed={} status={}",
+        "Run recorded: start={} end={} watermark={} found={} uploaded={} failed={} transform_failed={} deferred={} status={} destinations={}",
         start_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
         end_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        watermark_dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
         record.files_found,
         record.uploaded,
         record.failed,
+        record.transform_failed,
+        record.deferred,
         status,
+        destinations,
     );
 
     Ok(())
@@ -101,30 +141,125 @@ pub fn failed_list_path(state_file: &Path) -> PathBuf {
     state_file.with_file_name("failed_files.txt")
 }
 
-/// Load the list of previously failed file paths.
-pub fn load_failed_list(path: &Path) -> Result<Vec<PathBuf>> {
+/// Cached listing of one directory for incremental scans.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedDir {
+    pub mtime: DateTime<Utc>,
+    /// Names of subdirectories that were descended into.
+    pub subdirs: Vec<String>,
+    /// Names of files that passed the filters.
+    pub files: Vec<String>,
+}
+
+/// Directory listings from previous scans, keyed by the path relative to the scan root
+/// (`""` for the root itself).
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct DirCache {
+    /// When the last full (non-incremental) scan was done.
+    pub last_full_scan: Option<DateTime<Utc>>,
+    pub dirs: BTreeMap<String, CachedDir>,
+}
+
+/// Returns the path for the directory cache (alongside state_file).
+pub fn dir_cache_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("dir_cache.json")
+}
+
+/// Load the directory cache, or an empty one if none has been written yet.
+pub fn load_dir_cache(path: &Path) -> Result<DirCache> {
+    if !path.exists() {
+        return Ok(DirCache::default());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading directory cache {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Parsing directory cache {}", path.display()))
+}
+
+/// Save the directory cache, replacing the previous one atomically.
+pub fn save_dir_cache(path: &Path, cache: &DirCache) -> Result<()> {
+    write_json_atomic(path, cache)
+}
+
+/// All files written alongside state_file, so watch mode can ignore its own writes.
+pub fn state_files(state_file: &Path) -> Vec<PathBuf> {
+    vec![
+        state_file.to_path_buf(),
+        failed_list_path(state_file),
+        dead_letter_path(state_file),
+        queued_files_path(state_file),
+        manifest_path(state_file),
+        dir_cache_path(state_file),
+        upload_sessions_path(state_file),
+        reconcile_report_path(state_file),
+    ]
+}
+
+/// Returns the default path for the `reconcile` report (alongside state_file).
+pub fn reconcile_report_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("reconcile_report.tsv")
+}
+
+/// Returns the path for the dead-letter list (alongside state_file).
+pub fn dead_letter_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("dead_letter.txt")
+}
+
+/// Failure history of a file that could not be uploaded to one destination.
+#[derive(Clone, Debug)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    /// `--sink` name of the destination, or `None` for entries written before uploads were
+    /// tracked per destination (these apply to every destination).
+    pub destination: Option<String>,
+    /// Number of runs in which the upload failed.
+    pub attempts: u32,
+    pub first_failure: DateTime<Utc>,
+    pub last_failure: DateTime<Utc>,
+    pub last_error: String,
+}
+
+/// Load a failed-file list (retry or dead-letter).
+/// Format: path\tattempts\tfirst_failure\tlast_failure\tlast_error\tdestination. Lines holding
+/// only a path (written by older versions) count as one failed attempt with no recorded error.
+pub fn load_failed_list(path: &Path) -> Result<Vec<FailedFile>> {
     if !path.exists() {
         return Ok(vec![]);
     }
     let content = std::fs::read_to_string(path)
         .with_context(|| format!("Reading failed list {}", path.display()))?;
-    let paths = content
+    let now = Utc::now();
+    let parse_time = |field: Option<&str>| {
+        field
+            .and_then(|f| DateTime::parse_from_rfc3339(f.trim()).ok())
+            .map_or(now, |dt| dt.with_timezone(&Utc))
+    };
+    let entries = content
         .lines()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
-        .map(PathBuf::from)
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            FailedFile {
+                path: PathBuf::from(fields[0].trim()),
+                destination: fields.get(5).map(|f| f.trim().to_string()).filter(|f| !f.is_empty()),
+                attempts: fields.get(1).and_then(|f| f.trim().parse().ok()).unwrap_or(1),
+                first_failure: parse_time(fields.get(2).copied()),
+                last_failure: parse_time(fields.get(3).copied()),
+                last_error: fields.get(4).map(|f| f.trim().to_string()).unwrap_or_default(),
+            }
+        })
         .collect();
-    Ok(paths)
+    Ok(entries)
 }
 
-/// Save the list of failed file paths, overwriting the previous list.
+/// Save a failed-file list, overwriting the previous one.
 /// Passing an empty slice deletes the file.
-pub fn save_failed_list(path: &Path, failed: &[PathBuf]) -> Result<()> {
+pub fn save_failed_list(path: &Path, failed: &[FailedFile]) -> Result<()> {
     if failed.is_empty() {
         if path.exists() {
             std::fs::remove_file(path)
                 .with_context(|| format!("Removing failed list {}", path.display()))?;
-            info!("All retries succeeded, removed {}", path.display());
+            info!("No files left in {}, removed it", path.display());
         }
         return Ok(());
     }
@@ -136,16 +271,212 @@ pub fn save_failed_list(path: &Path, failed: &[PathBuf]) -> Result<()> {
         }
     }
 
-    let content = failed
+    let content: String = failed
         .iter()
-        .map(|p| p.to_string_lossy())
-        .collect::<Vec<_>>()
-        .join("\n")
-        + "\n";
+        .map(|f| {
+            // Keep the error on one line and out of the field separators
+            let error: String = f
+                .last_error
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect();
+            format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\n",
+                f.path.to_string_lossy(),
+                f.attempts,
+                f.first_failure.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                f.last_failure.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                error,
+                f.destination.as_deref().unwrap_or(""),
+            )
+        })
+        .collect();
 
     std::fs::write(path, content)
         .with_context(|| format!("Writing failed list {}", path.display()))?;
 
-    info!("{} file(s) remain in retry list {}", failed.len(), path.display());
+    info!("{} file(s) recorded in {}", failed.len(), path.display());
+    Ok(())
+}
+
+/// Returns the path for uploads waiting for an upload window (alongside state_file).
+pub fn queued_files_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("queued_files.txt")
+}
+
+/// Load uploads waiting for an upload window, as (path, destination) pairs.
+/// Format: path\tdestination
+pub fn load_queued_files(path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading queued uploads {}", path.display()))?;
+    let queued = content
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(file, destination)| (PathBuf::from(file.trim()), destination.trim().to_string()))
+        .collect();
+    Ok(queued)
+}
+
+/// Save uploads waiting for an upload window, overwriting the previous list.
+/// Passing an empty slice deletes the file.
+pub fn save_queued_files(path: &Path, queued: &[(PathBuf, String)]) -> Result<()> {
+    if queued.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Removing queued uploads {}", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Creating directory {}", parent.display()))?;
+        }
+    }
+
+    let content: String = queued
+        .iter()
+        .map(|(file, destination)| format!("{}\t{}\n", file.to_string_lossy(), destination))
+        .collect();
+    std::fs::write(path, content)
+        .with_context(|| format!("Writing queued uploads {}", path.display()))?;
+
+    info!("{} upload(s) queued in {}", queued.len(), path.display());
+    Ok(())
+}
+
+/// What was last seen (and uploaded) for a single file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "StoredManifestEntry")]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+    pub sha256: String,
+    /// Ids of the uploaded copies by destination (`--sink` name), where known: the uuid
+    /// assigned by rust-alc-api, or the object key or path for other sinks.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ids: BTreeMap<String, String>,
+}
+
+/// Manifest entry as stored, including the single `uuid` written by versions that only
+/// uploaded to rust-alc-api.
+#[derive(Deserialize)]
+struct StoredManifestEntry {
+    size: u64,
+    mtime: DateTime<Utc>,
+    sha256: String,
+    #[serde(default)]
+    ids: BTreeMap<String, String>,
+    #[serde(default)]
+    uuid: Option<String>,
+}
+
+impl From<StoredManifestEntry> for ManifestEntry {
+    fn from(stored: StoredManifestEntry) -> Self {
+        let mut ids = stored.ids;
+        if let Some(uuid) = stored.uuid {
+            ids.entry("alc-api".to_string()).or_insert(uuid);
+        }
+        ManifestEntry {
+            size: stored.size,
+            mtime: stored.mtime,
+            sha256: stored.sha256,
+            ids,
+        }
+    }
+}
+
+/// Per-file manifest keyed by the path relative to the scan root, using `/` separators.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+/// Returns the path for the file manifest (alongside state_file).
+pub fn manifest_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("manifest.json")
+}
+
+/// Load the file manifest. Returns `None` if no manifest has been written yet.
+pub fn load_manifest(path: &Path) -> Result<Option<Manifest>> {
+    if !path.exists() {
+        info!("No manifest found at {}", path.display());
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading manifest {}", path.display()))?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .with_context(|| format!("Parsing manifest {}", path.display()))?;
+    info!("Loaded manifest with {} file(s)", manifest.files.len());
+    Ok(Some(manifest))
+}
+
+/// Save the file manifest, replacing the previous one atomically.
+pub fn save_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    write_json_atomic(path, manifest)
+}
+
+/// A chunked upload that has been started but not finalized.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadSession {
+    pub upload_id: String,
+    /// Size, mtime and hash of the file when the session was started; a session is only
+    /// resumed for the same content.
+    pub size: u64,
+    pub mtime: DateTime<Utc>,
+    pub sha256: String,
+    pub chunk_size: u64,
+    /// Parts below this index have been acknowledged by the server.
+    pub next_part: u64,
+}
+
+/// Unfinished chunked uploads keyed by the path relative to the scan root (of the scanned
+/// file, also for `--transform` output).
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct UploadSessions {
+    pub sessions: BTreeMap<String, UploadSession>,
+}
+
+/// Returns the path for unfinished chunked upload sessions (alongside state_file).
+pub fn upload_sessions_path(state_file: &Path) -> PathBuf {
+    state_file.with_file_name("upload_sessions.json")
+}
+
+/// Load unfinished upload sessions, or none if nothing has been written yet.
+pub fn load_upload_sessions(path: &Path) -> Result<UploadSessions> {
+    if !path.exists() {
+        return Ok(UploadSessions::default());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Reading upload sessions {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Parsing upload sessions {}", path.display()))
+}
+
+/// Save unfinished upload sessions, replacing the previous file atomically.
+pub fn save_upload_sessions(path: &Path, sessions: &UploadSessions) -> Result<()> {
+    write_json_atomic(path, sessions)
+}
+
+/// Write `value` as JSON to a temporary file and rename it over `path`.
+fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Creating directory {}", parent.display()))?;
+        }
+    }
+
+    let content = serde_json::to_string_pretty(value)
+        .with_context(|| format!("Serializing {}", path.display()))?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, content)
+        .with_context(|| format!("Writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Replacing {}", path.display()))?;
     Ok(())
 }
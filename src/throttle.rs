@@ -0,0 +1,131 @@
+use chrono::Local;
+use futures_util::{Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cli::{Config, UploadWindow};
+
+/// Size of the pieces in-memory bodies are sent in, so they are paced like file streams.
+const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returned instead of starting an upload once the current upload window has closed.
+#[derive(Debug)]
+pub struct OutsideWindow;
+
+impl std::fmt::Display for OutsideWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "outside the upload windows")
+    }
+}
+
+impl std::error::Error for OutsideWindow {}
+
+/// Whether uploads may run at a given time, and how fast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Closed,
+    Unlimited,
+    BytesPerSec(u64),
+}
+
+/// Upload throughput limit shared by all uploads of a run.
+///
+/// A token bucket refilled at the rate of the current upload window (or `--max-bandwidth`)
+/// and holding at most one second's worth of bytes. Uploads still running when a window
+/// closes finish at that window's rate; new ones are not started until the next window.
+pub struct Throttle {
+    windows: Vec<UploadWindow>,
+    max_bandwidth: Option<u64>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    available: f64,
+    updated: Instant,
+    /// Rate of the last open window, in bytes per second.
+    rate: Option<u64>,
+}
+
+impl Throttle {
+    pub fn from_config(config: &Config) -> Arc<Self> {
+        Arc::new(Throttle {
+            windows: config.upload_window.clone(),
+            max_bandwidth: config.max_bandwidth,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                updated: Instant::now(),
+                rate: None,
+            }),
+        })
+    }
+
+    /// The limit in effect now. Without upload windows, uploads may always run.
+    pub fn limit(&self) -> Limit {
+        let rate = if self.windows.is_empty() {
+            self.max_bandwidth
+        } else {
+            let now = Local::now().naive_local();
+            match self.windows.iter().find(|w| w.contains(now)) {
+                Some(window) => window.rate.or(self.max_bandwidth),
+                None => return Limit::Closed,
+            }
+        };
+        rate.map_or(Limit::Unlimited, Limit::BytesPerSec)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.limit() != Limit::Closed
+    }
+
+    /// Wait until `bytes` may be sent. Concurrent callers share the bucket, so the limit
+    /// applies to the total throughput.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let rate = match self.limit() {
+                Limit::Closed => bucket.rate,
+                Limit::Unlimited => None,
+                Limit::BytesPerSec(rate) => Some(rate),
+            };
+            bucket.rate = rate;
+            let Some(rate) = rate.map(|r| r as f64) else {
+                return;
+            };
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+            bucket.available = (bucket.available + refill).min(rate) - bytes as f64;
+            bucket.updated = now;
+            if bucket.available >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.available / rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Pace a stream of byte chunks through the throttle.
+pub fn throttled<S, B, E>(stream: S, throttle: Arc<Throttle>) -> impl Stream<Item = Result<B, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    stream.then(move |chunk| {
+        let throttle = throttle.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                throttle.acquire(bytes.as_ref().len()).await;
+            }
+            chunk
+        }
+    })
+}
+
+/// A request body for content already in memory, paced by the throttle.
+pub fn bytes_body(bytes: &[u8], throttle: Arc<Throttle>) -> reqwest::Body {
+    let chunks: Vec<std::io::Result<Vec<u8>>> = bytes
+        .chunks(BODY_CHUNK_SIZE)
+        .map(|chunk| Ok(chunk.to_vec()))
+        .collect();
+    reqwest::Body::wrap_stream(throttled(futures_util::stream::iter(chunks), throttle))
+}
@@ -0,0 +1,336 @@
+use anyhow::{anyhow, bail, Context, Result};
+use globset::GlobSet;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info};
+
+use crate::cli::{Config, TransformStep};
+use crate::filter;
+use crate::scanner::{self, ChangedFile};
+use crate::state::ManifestEntry;
+use crate::uploader;
+
+/// Lines of a failed command's stderr kept in the error.
+const STDERR_LINES: usize = 5;
+
+/// A transform step failed. Reported apart from upload failures, though the file is retried
+/// the same way.
+#[derive(Debug)]
+pub struct TransformFailed {
+    pub path: PathBuf,
+    pub step: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for TransformFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transform {} failed for {}: {}", self.step, self.path.display(), self.reason)
+    }
+}
+
+impl std::error::Error for TransformFailed {}
+
+struct Pipeline {
+    pattern: String,
+    matcher: GlobSet,
+    steps: Vec<TransformStep>,
+}
+
+/// The `--transform` pipelines, run on a file before it is uploaded.
+///
+/// Each step reads the previous step's output (the scanned file for the first step) and
+/// writes a new file in a work directory under the system temp directory. The manifest and
+/// retry lists keep tracking the scanned file; only the upload sees the output.
+pub struct Transforms {
+    pipelines: Vec<Pipeline>,
+    timeout: Duration,
+}
+
+impl Transforms {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut pipelines: Vec<Pipeline> = Vec::new();
+        for rule in &config.transform {
+            match pipelines.iter_mut().find(|p| p.pattern == rule.pattern) {
+                Some(pipeline) => pipeline.steps.push(rule.step.clone()),
+                None => pipelines.push(Pipeline {
+                    pattern: rule.pattern.clone(),
+                    matcher: filter::build_set(std::iter::once(rule.pattern.as_str()))?,
+                    steps: vec![rule.step.clone()],
+                }),
+            }
+        }
+        Ok(Transforms {
+            pipelines,
+            timeout: Duration::from_secs(config.transform_timeout_secs),
+        })
+    }
+
+    /// Steps to run on a file, if a pattern matches it.
+    pub fn steps(&self, rel_path: &str) -> Option<&[TransformStep]> {
+        self.pipelines
+            .iter()
+            .find(|p| p.matcher.is_match(rel_path))
+            .map(|p| p.steps.as_slice())
+    }
+
+    /// Whether two files get the same steps (possibly none).
+    pub fn same_steps(&self, a: &str, b: &str) -> bool {
+        let pipeline = |rel_path: &str| self.pipelines.iter().position(|p| p.matcher.is_match(rel_path));
+        pipeline(a) == pipeline(b)
+    }
+
+    /// Relative path a file is uploaded under once its steps have run.
+    pub fn target_path(&self, rel_path: &str) -> String {
+        let steps = self.steps(rel_path).unwrap_or_default();
+        let (dir, name) = split_rel_path(rel_path);
+        join_rel_path(dir, &steps.iter().fold(name.to_string(), |name, step| renamed(&name, step)))
+    }
+
+    /// The file to upload: the scanned file itself, or the output of its steps with the
+    /// output's size and hash. The scanned file must be unchanged before and after the steps,
+    /// as the manifest records its scanned size and hash for the upload.
+    pub async fn prepare<'a>(&self, file: &'a ChangedFile) -> Result<Prepared<'a>> {
+        let Some(steps) = self.steps(&file.rel_path) else {
+            return Ok(Prepared { file: Cow::Borrowed(file), _work: None });
+        };
+        uploader::ensure_unchanged(file).await?;
+
+        let work = WorkDir::new();
+        let (dir, name) = split_rel_path(&file.rel_path);
+        let mtime = SystemTime::from(file.entry.mtime);
+        let mut current = file.path.clone();
+        let mut name = name.to_string();
+        for (i, step) in steps.iter().enumerate() {
+            let next_name = renamed(&name, step);
+            if let TransformStep::Ext(_) = step {
+                name = next_name;
+                continue;
+            }
+            let output = work.0.join(i.to_string()).join(&next_name);
+            debug!("Transform {} on {}: {} -> {}", step, file.path.display(), current.display(), output.display());
+            self.run_step(step, &current, &output, mtime)
+                .await
+                .map_err(|e| failed(file, &step.to_string(), e))?;
+            current = output;
+            name = next_name;
+        }
+        // A pipeline ending in `ext:` still needs a file under the new name
+        if current.file_name().is_none_or(|n| n.to_string_lossy() != name) {
+            let output = work.0.join("out").join(&name);
+            link_or_copy(&current, &output, mtime).map_err(|e| failed(file, "output", e))?;
+            current = output;
+        }
+
+        uploader::ensure_unchanged(file).await?;
+        let output = current.clone();
+        let (size, sha256) = tokio::task::spawn_blocking(move || -> Result<(u64, String)> {
+            let size = std::fs::metadata(&output)
+                .with_context(|| format!("Reading metadata for {}", output.display()))?
+                .len();
+            Ok((size, scanner::hash_file(&output)?))
+        })
+        .await
+        .context("Hashing task panicked")?
+        .map_err(|e| failed(file, "output", e))?;
+        info!(
+            "Transformed {} ({} bytes) into {} ({} bytes)",
+            file.path.display(),
+            file.entry.size,
+            name,
+            size
+        );
+        Ok(Prepared {
+            file: Cow::Owned(ChangedFile {
+                path: current,
+                rel_path: join_rel_path(dir, &name),
+                entry: ManifestEntry {
+                    size,
+                    sha256,
+                    ..file.entry.clone()
+                },
+                ctime: file.ctime,
+                origin: Some(file.rel_path.clone()),
+            }),
+            _work: Some(work),
+        })
+    }
+
+    async fn run_step(&self, step: &TransformStep, input: &Path, output: &Path, mtime: SystemTime) -> Result<()> {
+        let parent = output.parent().context("Output path has no parent")?;
+        std::fs::create_dir_all(parent).with_context(|| format!("Creating directory {}", parent.display()))?;
+        match step {
+            TransformStep::Exec(args) => self.exec(args, input, output).await?,
+            TransformStep::Gzip => gzip(input, output).await?,
+            TransformStep::Ext(_) => unreachable!("ext: only renames"),
+        }
+        let written = std::fs::metadata(output).map(|m| m.is_file() && m.len() > 0).unwrap_or(false);
+        if !written && std::fs::metadata(input).map(|m| m.len() > 0).unwrap_or(true) {
+            bail!("no output was written to {}", output.display());
+        }
+        // Uploads send the original mtime and check it against the file they read
+        set_mtime(output, mtime)
+    }
+
+    async fn exec(&self, args: &[String], input: &Path, output: &Path) -> Result<()> {
+        let fill = |arg: &String| {
+            arg.replace("{input}", &input.to_string_lossy())
+                .replace("{output}", &output.to_string_lossy())
+        };
+        let stdin = if args.iter().any(|a| a.contains("{input}")) {
+            Stdio::null()
+        } else {
+            File::open(input)
+                .with_context(|| format!("Opening {}", input.display()))?
+                .into()
+        };
+        let stdout = if args.iter().any(|a| a.contains("{output}")) {
+            Stdio::null()
+        } else {
+            File::create(output)
+                .with_context(|| format!("Creating {}", output.display()))?
+                .into()
+        };
+        let child = tokio::process::Command::new(fill(&args[0]))
+            .args(args[1..].iter().map(fill))
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Starting {}", args[0]))?;
+        let result = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .map_err(|_| anyhow!("{} did not finish within {} s", args[0], self.timeout.as_secs()))?
+            .with_context(|| format!("Waiting for {}", args[0]))?;
+        if !result.status.success() {
+            bail!("{} exited with {}: {}", args[0], result.status, stderr_tail(&result.stderr));
+        }
+        Ok(())
+    }
+}
+
+/// A file ready for upload. Transformed files are removed with their work directory when
+/// this is dropped.
+pub struct Prepared<'a> {
+    file: Cow<'a, ChangedFile>,
+    _work: Option<WorkDir>,
+}
+
+impl Deref for Prepared<'_> {
+    type Target = ChangedFile;
+
+    fn deref(&self) -> &ChangedFile {
+        &self.file
+    }
+}
+
+/// A directory for one file's step outputs, removed on drop.
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        WorkDir(std::env::temp_dir().join(format!(
+            "smb-watch-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                debug!("Could not remove transform directory {}: {}", self.0.display(), e);
+            }
+        }
+    }
+}
+
+fn failed(file: &ChangedFile, step: &str, e: anyhow::Error) -> anyhow::Error {
+    TransformFailed {
+        path: file.path.clone(),
+        step: step.to_string(),
+        reason: format!("{:#}", e),
+    }
+    .into()
+}
+
+/// Name of the file after a step.
+fn renamed(name: &str, step: &TransformStep) -> String {
+    match step {
+        TransformStep::Exec(_) => name.to_string(),
+        TransformStep::Gzip => format!("{}.gz", name),
+        TransformStep::Ext(ext) => match name.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => format!("{}.{}", stem, ext),
+            _ => format!("{}.{}", name, ext),
+        },
+    }
+}
+
+fn split_rel_path(rel_path: &str) -> (Option<&str>, &str) {
+    match rel_path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, rel_path),
+    }
+}
+
+fn join_rel_path(dir: Option<&str>, name: &str) -> String {
+    match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name.to_string(),
+    }
+}
+
+/// Gzip-compress a file. The header carries no name or time, so the same input always gives
+/// the same output.
+async fn gzip(input: &Path, output: &Path) -> Result<()> {
+    let (input, output) = (input.to_path_buf(), output.to_path_buf());
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut reader = BufReader::new(File::open(&input).with_context(|| format!("Opening {}", input.display()))?);
+        let writer = BufWriter::new(File::create(&output).with_context(|| format!("Creating {}", output.display()))?);
+        let mut encoder = flate2::GzBuilder::new().write(writer, flate2::Compression::default());
+        std::io::copy(&mut reader, &mut encoder).with_context(|| format!("Compressing {}", input.display()))?;
+        encoder
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .with_context(|| format!("Writing {}", output.display()))
+    })
+    .await
+    .context("Gzip task panicked")?
+}
+
+/// Put a file under another name, by a hard link where the file system allows it.
+fn link_or_copy(from: &Path, to: &Path, mtime: SystemTime) -> Result<()> {
+    let parent = to.parent().context("Output path has no parent")?;
+    std::fs::create_dir_all(parent).with_context(|| format!("Creating directory {}", parent.display()))?;
+    if std::fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to).with_context(|| format!("Copying {} to {}", from.display(), to.display()))?;
+    set_mtime(to, mtime)
+}
+
+fn set_mtime(path: &Path, mtime: SystemTime) -> Result<()> {
+    File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(mtime))
+        .with_context(|| format!("Setting mtime of {}", path.display()))
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let text = String::from_utf8_lossy(stderr);
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    if lines.is_empty() {
+        return "no error output".to_string();
+    }
+    lines[lines.len().saturating_sub(STDERR_LINES)..].join(" / ")
+}
@@ -1,9 +1,33 @@
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::multipart::{Form, Part};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::time::Duration;
-use tracing::{info, warn};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+
+use crate::cli::{Config, MetadataField, UploadMode};
+use crate::content_type::ContentTypes;
+use crate::scanner::ChangedFile;
+use crate::sink::StoredCopy;
+use crate::state::{self, UploadSession, UploadSessions};
+use crate::throttle::{self, Throttle};
+
+/// Header carrying the key that lets the server deduplicate repeated uploads.
+const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Read size for streamed uploads; memory use per upload stays around this size.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Serialize)]
 struct CreateFileRequest {
@@ -11,6 +35,119 @@ struct CreateFileRequest {
     #[serde(rename = "type")]
     file_type: String,
     content: String, // base64 encoded
+    #[serde(flatten)]
+    metadata: FileMetadata,
+}
+
+/// Which metadata fields are sent, and the source of the files in this run.
+pub struct MetadataOptions {
+    omit: Vec<MetadataField>,
+    source_host: Option<String>,
+    source_share: Option<String>,
+    content_types: ContentTypes,
+}
+
+impl MetadataOptions {
+    pub fn from_config(config: &Config) -> Self {
+        let (source_host, source_share) = match &config.local_path {
+            Some(local_path) => (
+                std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).ok(),
+                Some(local_path.to_string_lossy().into_owned()),
+            ),
+            None => (
+                Some(config.smb_host.clone()),
+                Some(format!("{}/{}", config.smb_share, config.smb_path.replace('\\', "/"))),
+            ),
+        };
+        MetadataOptions {
+            omit: config.omit_metadata.clone(),
+            source_host,
+            source_share,
+            content_types: ContentTypes::from_config(config),
+        }
+    }
+
+    /// MIME type to send for the file (see `ContentTypes`).
+    pub async fn content_type(&self, path: &Path) -> Result<String> {
+        self.content_types.detect(path).await
+    }
+
+    fn includes(&self, field: MetadataField) -> bool {
+        !self.omit.contains(&field)
+    }
+
+    pub fn for_file(&self, file: &ChangedFile) -> FileMetadata {
+        let ctime = file
+            .ctime
+            .filter(|_| self.includes(MetadataField::Ctime))
+            .map(|t| t.to_rfc3339());
+        let source = self.includes(MetadataField::Source);
+        FileMetadata {
+            relative_path: self
+                .includes(MetadataField::RelativePath)
+                .then(|| file.rel_path.clone()),
+            size: self.includes(MetadataField::Size).then_some(file.entry.size),
+            mtime: self
+                .includes(MetadataField::Mtime)
+                .then(|| file.entry.mtime.to_rfc3339()),
+            ctime,
+            sha256: self
+                .includes(MetadataField::Sha256)
+                .then(|| file.entry.sha256.clone()),
+            source_host: self.source_host.clone().filter(|_| source),
+            source_share: self.source_share.clone().filter(|_| source),
+            client_version: self
+                .includes(MetadataField::Version)
+                .then_some(env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// Metadata describing an uploaded file. Absent fields are not sent.
+#[derive(Serialize, Default)]
+pub struct FileMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relative_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ctime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_share: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_version: Option<&'static str>,
+}
+
+impl FileMetadata {
+    /// Field names and values, as sent in multipart text fields and raw-mode headers.
+    pub fn pairs(&self) -> Vec<(&'static str, String)> {
+        [
+            ("relative_path", self.relative_path.clone()),
+            ("size", self.size.map(|n| n.to_string())),
+            ("mtime", self.mtime.clone()),
+            ("ctime", self.ctime.clone()),
+            ("sha256", self.sha256.clone()),
+            ("source_host", self.source_host.clone()),
+            ("source_share", self.source_share.clone()),
+            ("client_version", self.client_version.map(str::to_string)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|v| (name, v)))
+        .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct RenameFileRequest<'a> {
+    filename: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relative_path: Option<&'a str>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -18,68 +155,701 @@ pub struct UploadResponse {
     pub uuid: String,
 }
 
-pub fn build_client() -> Result<reqwest::Client> {
-    reqwest::Client::builder()
-        .timeout(Duration::from_secs(60))
-        .build()
-        .context("Building HTTP client")
+/// A file the server holds, as listed by `GET {files_url}`. Fields other than the uuid are
+/// only present where the server recorded them.
+#[derive(Deserialize, Debug)]
+pub struct RemoteFile {
+    pub uuid: String,
+    #[serde(default)]
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub relative_path: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// A file listing: either every file at once, or a page with the cursor for the next one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileListResponse {
+    Page {
+        files: Vec<RemoteFile>,
+        next_cursor: Option<String>,
+    },
+    All(Vec<RemoteFile>),
+}
+
+#[derive(Deserialize)]
+struct FileInfoResponse {
+    size: Option<u64>,
+    sha256: Option<String>,
+}
+
+/// The file changed between scanning and reading, or while it was being read.
+/// The upload should be deferred to the next run rather than counted as a failure.
+#[derive(Debug)]
+pub struct FileUnstable {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for FileUnstable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} changed while being read", self.path.display())
+    }
+}
+
+impl std::error::Error for FileUnstable {}
+
+/// The server answered with a non-success status.
+#[derive(Debug)]
+pub struct HttpError {
+    pub action: &'static str,
+    pub status: StatusCode,
+    /// Server-requested wait, only taken from 429 and 503 responses.
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed with HTTP {}: {}", self.action, self.status, self.body)
+    }
 }
 
+impl std::error::Error for HttpError {}
+
 pub async fn upload_file(
     client: &reqwest::Client,
     url: &str,
-    path: &Path,
+    file: &ChangedFile,
     token: &str,
-) -> Result<()> {
-    let bytes = tokio::fs::read(path)
-        .await
-        .with_context(|| format!("Reading file {}", path.display()))?;
+    mode: UploadMode,
+    metadata: &MetadataOptions,
+    throttle: &Arc<Throttle>,
+) -> Result<Option<String>> {
+    let path = file.path.as_path();
+    let expected = (file.entry.size, SystemTime::from(file.entry.mtime));
+
+    if stat(path).await? != expected {
+        return Err(FileUnstable { path: path.to_path_buf() }.into());
+    }
 
     let filename = path
         .file_name()
         .map(|n| n.to_string_lossy().into_owned())
         .unwrap_or_else(|| "unknown".to_string());
 
-    let mime = mime_guess::from_path(path)
-        .first_or_octet_stream()
-        .to_string();
+    let mime = metadata.content_type(path).await?;
+
+    let metadata = metadata.for_file(file);
+    let hasher = Arc::new(Mutex::new(Sha256::new()));
+    let (post_url, request) = match mode {
+        UploadMode::Json => {
+            let bytes = tokio::fs::read(path)
+                .await
+                .with_context(|| format!("Reading file {}", path.display()))?;
+            if stat(path).await? != expected || bytes.len() as u64 != expected.0 {
+                return Err(FileUnstable { path: path.to_path_buf() }.into());
+            }
+            let body = serde_json::to_vec(&CreateFileRequest {
+                filename: filename.clone(),
+                file_type: mime,
+                content: STANDARD.encode(&bytes),
+                metadata,
+            })?;
+            let request = client
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_LENGTH, body.len())
+                .body(throttle::bytes_body(&body, throttle.clone()));
+            (url.to_string(), request)
+        }
+        UploadMode::Multipart => {
+            let body = file_body(path, expected.0, hasher.clone(), throttle.clone()).await?;
+            let part = Part::stream_with_length(body, expected.0)
+                .file_name(filename.clone())
+                .mime_str(&mime)?;
+            let form = metadata
+                .pairs()
+                .into_iter()
+                .fold(Form::new(), |form, (name, value)| form.text(name, value))
+                .part("file", part);
+            (url.to_string(), client.post(url).multipart(form))
+        }
+        UploadMode::Raw => {
+            let raw_url = format!("{}/raw", url);
+            let body = file_body(path, expected.0, hasher.clone(), throttle.clone()).await?;
+            let request = metadata.pairs().into_iter().fold(
+                client
+                    .post(&raw_url)
+                    .header(CONTENT_TYPE, &mime)
+                    .header(CONTENT_LENGTH, expected.0)
+                    .header("X-Filename", percent_encode(&filename)),
+                |request, (name, value)| {
+                    request.header(format!("X-{}", name.replace('_', "-")), percent_encode(&value))
+                },
+            );
+            let request = request.body(body);
+            (raw_url, request)
+        }
+    };
+
+    let key = idempotency_key(file);
+    let response = match request.bearer_auth(token).header(IDEMPOTENCY_KEY, &key).send().await {
+        Ok(response) => response,
+        // A streamed body ends early when the file shrinks mid-upload
+        Err(_) if stat(path).await? != expected => {
+            return Err(FileUnstable { path: path.to_path_buf() }.into());
+        }
+        Err(e) => return Err(e).with_context(|| format!("POST to {}", post_url)),
+    };
+
+    if response.status() == StatusCode::CONFLICT {
+        return already_stored(response, path).await;
+    }
+    let response = check_status(response, "Upload").await?;
+
+    let uuid = match response.json::<UploadResponse>().await {
+        Ok(resp) => Some(resp.uuid),
+        Err(e) => {
+            warn!("Uploaded {} but could not parse response: {}", path.display(), e);
+            None
+        }
+    };
+
+    // Streamed content can only be checked once it has been sent
+    if mode != UploadMode::Json {
+        let sent = hex::encode(std::mem::take(&mut *hasher.lock().unwrap()).finalize());
+        if sent != file.entry.sha256 || stat(path).await? != expected {
+            if let Some(uuid) = &uuid {
+                if let Err(e) = delete_file(client, url, uuid, token).await {
+                    warn!("Could not remove inconsistent upload of {} (uuid: {}): {:#}", filename, uuid, e);
+                }
+            }
+            return Err(FileUnstable { path: path.to_path_buf() }.into());
+        }
+    }
+
+    if let Some(uuid) = &uuid {
+        info!("Uploaded {} -> uuid: {}", path.display(), uuid);
+    }
+    Ok(uuid)
+}
+
+/// Unfinished chunked uploads, saved after every acknowledged part so that a later
+/// attempt or run resumes where the previous one stopped.
+pub struct SessionStore {
+    path: PathBuf,
+    sessions: Mutex<UploadSessions>,
+    /// Part size for new sessions; sessions started with another size are not resumed.
+    chunk_size: u64,
+}
+
+impl SessionStore {
+    pub fn load(path: PathBuf, chunk_size: u64) -> Result<Self> {
+        let sessions = state::load_upload_sessions(&path)?;
+        Ok(SessionStore { path, sessions: Mutex::new(sessions), chunk_size })
+    }
+
+    fn get(&self, rel_path: &str) -> Option<UploadSession> {
+        self.sessions.lock().unwrap().sessions.get(rel_path).cloned()
+    }
+
+    fn put(&self, rel_path: &str, session: &UploadSession) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.sessions.insert(rel_path.to_string(), session.clone());
+        state::save_upload_sessions(&self.path, &sessions)
+    }
+
+    fn remove(&self, rel_path: &str) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.sessions.remove(rel_path).is_some() {
+            state::save_upload_sessions(&self.path, &sessions)?;
+        }
+        Ok(())
+    }
+
+    /// Forget sessions for which `keep` returns false, e.g. files deleted from the share.
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) -> Result<()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.sessions.len();
+        sessions.sessions.retain(|rel, _| keep(rel));
+        if sessions.sessions.len() != before {
+            info!("Discarded {} unfinished upload session(s)", before - sessions.sessions.len());
+            state::save_upload_sessions(&self.path, &sessions)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct CreateSessionRequest<'a> {
+    filename: &'a str,
+    #[serde(rename = "type")]
+    file_type: &'a str,
+    size: u64,
+    sha256: &'a str,
+    chunk_size: u64,
+    #[serde(flatten)]
+    metadata: FileMetadata,
+}
+
+#[derive(Deserialize)]
+struct CreateSessionResponse {
+    upload_id: String,
+}
+
+#[derive(Deserialize)]
+struct SessionStatusResponse {
+    received_parts: u64,
+}
 
-    let body = CreateFileRequest {
-        filename: filename.clone(),
-        file_type: mime,
-        content: STANDARD.encode(&bytes),
+/// Upload a file in fixed-size parts over a resumable session:
+/// `POST {url}/uploads`, `PUT {url}/uploads/{id}/parts/{n}`, `POST {url}/uploads/{id}/complete`.
+/// Each part is its own request, and acknowledged parts are recorded in `sessions`, so an
+/// interrupted upload continues from the first unacknowledged part.
+pub async fn upload_file_chunked(
+    client: &reqwest::Client,
+    url: &str,
+    file: &ChangedFile,
+    token: &str,
+    sessions: &SessionStore,
+    metadata: &MetadataOptions,
+    throttle: &Arc<Throttle>,
+) -> Result<Option<String>> {
+    let path = file.path.as_path();
+    let chunk_size = sessions.chunk_size;
+    let expected = (file.entry.size, SystemTime::from(file.entry.mtime));
+
+    if stat(path).await? != expected {
+        return Err(FileUnstable { path: path.to_path_buf() }.into());
+    }
+
+    let filename = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "unknown".to_string());
+    let uploads_url = format!("{}/uploads", url);
+    let parts = file.entry.size.div_ceil(chunk_size);
+
+    let resumable = sessions.get(file.source_rel_path()).filter(|s| {
+        s.size == file.entry.size
+            && s.mtime == file.entry.mtime
+            && s.sha256 == file.entry.sha256
+            && s.chunk_size == chunk_size
+    });
+    let resumed = match resumable {
+        Some(session) => session_status(client, &uploads_url, &session.upload_id, token)
+            .await?
+            .map(|received| UploadSession { next_part: received.min(parts), ..session }),
+        None => None,
     };
+    let mut session = match resumed {
+        Some(session) => {
+            info!(
+                "Resuming upload of {} at part {}/{}",
+                path.display(),
+                session.next_part + 1,
+                parts
+            );
+            session
+        }
+        None => {
+            if let Some(stale) = sessions.get(file.source_rel_path()) {
+                abort_session(client, &uploads_url, &stale.upload_id, token).await;
+            }
+            let mime = metadata.content_type(path).await?;
+            // Size and hash are always part of the session request
+            let metadata = FileMetadata {
+                size: None,
+                sha256: None,
+                ..metadata.for_file(file)
+            };
+            let response = client
+                .post(&uploads_url)
+                .bearer_auth(token)
+                .header(IDEMPOTENCY_KEY, idempotency_key(file))
+                .json(&CreateSessionRequest {
+                    filename: &filename,
+                    file_type: &mime,
+                    size: file.entry.size,
+                    sha256: &file.entry.sha256,
+                    chunk_size,
+                    metadata,
+                })
+                .send()
+                .await
+                .with_context(|| format!("POST to {}", uploads_url))?;
+            if response.status() == StatusCode::CONFLICT {
+                sessions.remove(file.source_rel_path())?;
+                return already_stored(response, path).await;
+            }
+            let created: CreateSessionResponse = check_status(response, "Start upload")
+                .await?
+                .json()
+                .await
+                .context("Parsing upload session response")?;
+            info!("Started chunked upload of {} ({} part(s))", path.display(), parts);
+            UploadSession {
+                upload_id: created.upload_id,
+                size: file.entry.size,
+                mtime: file.entry.mtime,
+                sha256: file.entry.sha256.clone(),
+                chunk_size,
+                next_part: 0,
+            }
+        }
+    };
+    sessions.put(file.source_rel_path(), &session)?;
+
+    let session_url = format!("{}/{}", uploads_url, session.upload_id);
+    let mut f = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Opening file {}", path.display()))?;
+    let mut buf = vec![0u8; chunk_size.min(file.entry.size) as usize];
+    while session.next_part < parts {
+        let offset = session.next_part * chunk_size;
+        let len = chunk_size.min(file.entry.size - offset) as usize;
+        f.seek(SeekFrom::Start(offset))
+            .await
+            .with_context(|| format!("Seeking in {}", path.display()))?;
+        if f.read_exact(&mut buf[..len]).await.is_err() {
+            // Shorter than when scanned
+            return Err(FileUnstable { path: path.to_path_buf() }.into());
+        }
 
+        let part_url = format!("{}/parts/{}", session_url, session.next_part);
+        let response = client
+            .put(&part_url)
+            .bearer_auth(token)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, len)
+            .body(throttle::bytes_body(&buf[..len], throttle.clone()))
+            .send()
+            .await
+            .with_context(|| format!("PUT to {}", part_url))?;
+        check_status(response, "Upload part").await?;
+
+        session.next_part += 1;
+        sessions.put(file.source_rel_path(), &session)?;
+        debug!("Uploaded part {}/{} of {}", session.next_part, parts, path.display());
+    }
+
+    // The server checks size and hash; a file that changed meanwhile is started over later
+    if stat(path).await? != expected {
+        abort_session(client, &uploads_url, &session.upload_id, token).await;
+        sessions.remove(file.source_rel_path())?;
+        return Err(FileUnstable { path: path.to_path_buf() }.into());
+    }
+
+    let complete_url = format!("{}/complete", session_url);
     let response = client
-        .post(url)
+        .post(&complete_url)
         .bearer_auth(token)
-        .json(&body)
         .send()
         .await
-        .with_context(|| format!("POST to {}", url))?;
-
-    let status = response.status();
-
-    if !status.is_success() {
-        let body = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "(unreadable body)".to_string());
-        return Err(anyhow::anyhow!(
-            "Upload failed with HTTP {}: {}",
-            status,
-            body.trim()
-        ));
+        .with_context(|| format!("POST to {}", complete_url))?;
+    if response.status() == StatusCode::CONFLICT {
+        sessions.remove(file.source_rel_path())?;
+        return already_stored(response, path).await;
     }
+    let response = match check_status(response, "Finalize upload").await {
+        Ok(response) => response,
+        Err(e) => {
+            // A rejected session cannot be finalized later either
+            if e.downcast_ref::<HttpError>().is_some_and(|h| h.status.is_client_error()) {
+                sessions.remove(file.source_rel_path())?;
+            }
+            return Err(e);
+        }
+    };
+    sessions.remove(file.source_rel_path())?;
 
     match response.json::<UploadResponse>().await {
         Ok(resp) => {
-            info!("Uploaded {} -> uuid: {}", filename, resp.uuid);
+            info!("Uploaded {} -> uuid: {}", path.display(), resp.uuid);
+            Ok(Some(resp.uuid))
         }
         Err(e) => {
-            warn!("Uploaded {} but could not parse response: {}", filename, e);
+            warn!("Uploaded {} but could not parse response: {}", path.display(), e);
+            Ok(None)
         }
     }
+}
+
+/// Key identifying this content at this path, sent as `Idempotency-Key` so the server can
+/// recognise an upload it has already stored (e.g. when the previous run died before
+/// recording it).
+fn idempotency_key(file: &ChangedFile) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(file.entry.sha256.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(file.rel_path.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// The server answered 409: it already holds this content under this key.
+async fn already_stored(response: reqwest::Response, path: &Path) -> Result<Option<String>> {
+    let uuid = response.json::<UploadResponse>().await.ok().map(|r| r.uuid);
+    info!(
+        "Already on server, skipping upload: {} (uuid: {})",
+        path.display(),
+        uuid.as_deref().unwrap_or("unknown")
+    );
+    Ok(uuid)
+}
+
+/// Ask the server whether it already holds this content (`GET {url}/by-key/{key}`), returning
+/// the stored copy's uuid. Errors are logged and treated as "not stored", since the upload
+/// itself carries the same key.
+pub async fn find_existing(client: &reqwest::Client, url: &str, file: &ChangedFile, token: &str) -> Option<String> {
+    let check_url = format!("{}/by-key/{}", url, idempotency_key(file));
+    let result = match client.get(&check_url).bearer_auth(token).send().await {
+        Ok(response) if response.status() == StatusCode::NOT_FOUND => return None,
+        Ok(response) => match check_status(response, "Pre-flight check").await {
+            Ok(response) => response.json::<UploadResponse>().await.map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e.into()),
+    };
+    match result {
+        Ok(found) => {
+            info!("Already on server, skipping upload: {} (uuid: {})", file.path.display(), found.uuid);
+            Some(found.uuid)
+        }
+        Err(e) => {
+            warn!("Pre-flight check for {} failed, uploading anyway: {:#}", file.path.display(), e);
+            None
+        }
+    }
+}
+
+/// Number of parts the server has received for a session, or `None` if it no longer exists.
+async fn session_status(
+    client: &reqwest::Client,
+    uploads_url: &str,
+    upload_id: &str,
+    token: &str,
+) -> Result<Option<u64>> {
+    let url = format!("{}/{}", uploads_url, upload_id);
+    let response = client
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("GET {}", url))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let status: SessionStatusResponse = check_status(response, "Upload status")
+        .await?
+        .json()
+        .await
+        .context("Parsing upload status response")?;
+    Ok(Some(status.received_parts))
+}
+
+/// Best-effort cancellation of a session that will not be finalized.
+async fn abort_session(client: &reqwest::Client, uploads_url: &str, upload_id: &str, token: &str) {
+    let url = format!("{}/{}", uploads_url, upload_id);
+    let result = match client.delete(&url).bearer_auth(token).send().await {
+        Ok(response) => check_status(response, "Abort upload").await.map(|_| ()),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        debug!("Could not abort upload session {}: {:#}", upload_id, e);
+    }
+}
+
+/// Header values are percent-encoded UTF-8, leaving RFC 3986 unreserved characters as is.
+const HEADER_VALUE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
 
+pub fn percent_encode(value: &str) -> String {
+    utf8_percent_encode(value, HEADER_VALUE).to_string()
+}
+
+/// Stream the first `size` bytes of the file in fixed-size chunks paced by `throttle`,
+/// feeding them to `hasher`.
+pub async fn file_body(
+    path: &Path,
+    size: u64,
+    hasher: Arc<Mutex<Sha256>>,
+    throttle: Arc<Throttle>,
+) -> Result<reqwest::Body> {
+    let f = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Opening file {}", path.display()))?;
+    let stream = ReaderStream::with_capacity(f.take(size), STREAM_CHUNK_SIZE).inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            hasher.lock().unwrap().update(chunk);
+        }
+    });
+    Ok(reqwest::Body::wrap_stream(throttle::throttled(stream, throttle)))
+}
+
+/// Update the stored file's name and relative path after a local rename (PATCH {files_url}/{uuid}).
+pub async fn rename_file(
+    client: &reqwest::Client,
+    files_url: &str,
+    uuid: &str,
+    rel_path: &str,
+    token: &str,
+    metadata: &MetadataOptions,
+) -> Result<()> {
+    let filename = rel_path.rsplit('/').next().unwrap_or(rel_path);
+    let relative_path = metadata.includes(MetadataField::RelativePath).then_some(rel_path);
+    let url = format!("{}/{}", files_url, uuid);
+    let response = client
+        .patch(&url)
+        .bearer_auth(token)
+        .json(&RenameFileRequest { filename, relative_path })
+        .send()
+        .await
+        .with_context(|| format!("PATCH to {}", url))?;
+    check_status(response, "Rename").await?;
+    Ok(())
+}
+
+/// Delete the stored file (DELETE {files_url}/{uuid}).
+pub async fn delete_file(client: &reqwest::Client, files_url: &str, uuid: &str, token: &str) -> Result<()> {
+    let url = format!("{}/{}", files_url, uuid);
+    let response = client
+        .delete(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("DELETE to {}", url))?;
+    check_status(response, "Delete").await?;
     Ok(())
 }
+
+/// Archive the stored file (POST {files_url}/{uuid}/archive).
+pub async fn archive_file(client: &reqwest::Client, files_url: &str, uuid: &str, token: &str) -> Result<()> {
+    let url = format!("{}/{}/archive", files_url, uuid);
+    let response = client
+        .post(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("POST to {}", url))?;
+    check_status(response, "Archive").await?;
+    Ok(())
+}
+
+/// List the tenant's files (GET {files_url}), following `next_cursor` (`?cursor=`) across pages.
+pub async fn list_files(client: &reqwest::Client, files_url: &str, token: &str) -> Result<Vec<RemoteFile>> {
+    let mut files = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut request = client.get(files_url).bearer_auth(token);
+        if let Some(cursor) = &cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("GET {}", files_url))?;
+        let page: FileListResponse = check_status(response, "List files")
+            .await?
+            .json()
+            .await
+            .context("Parsing file list response")?;
+        match page {
+            FileListResponse::All(all) => {
+                files.extend(all);
+                return Ok(files);
+            }
+            FileListResponse::Page { files: page, next_cursor } => {
+                files.extend(page);
+                match next_cursor {
+                    Some(next) if cursor.as_ref() == Some(&next) => {
+                        anyhow::bail!("File list returned the same cursor {:?} twice", next)
+                    }
+                    Some(next) => cursor = Some(next),
+                    None => return Ok(files),
+                }
+            }
+        }
+    }
+}
+
+/// Size and hash of the stored file as recorded by the server (GET {files_url}/{uuid}), or
+/// computed by downloading it (GET {files_url}/{uuid}/content) if the server reports neither.
+pub async fn stored_file(client: &reqwest::Client, files_url: &str, uuid: &str, token: &str) -> Result<StoredCopy> {
+    let url = format!("{}/{}", files_url, uuid);
+    let response = client
+        .get(&url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("GET {}", url))?;
+    let info: FileInfoResponse = check_status(response, "File info")
+        .await?
+        .json()
+        .await
+        .context("Parsing file info response")?;
+    if let (Some(size), Some(sha256)) = (info.size, info.sha256) {
+        return Ok(StoredCopy { size, sha256 });
+    }
+
+    let content_url = format!("{}/content", url);
+    let response = client
+        .get(&content_url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("GET {}", content_url))?;
+    StoredCopy::read(check_status(response, "Download").await?).await
+}
+
+pub async fn check_status(response: reqwest::Response, action: &'static str) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => retry_after(response.headers()),
+        _ => None,
+    };
+    let body = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "(unreadable body)".to_string());
+    Err(HttpError {
+        action,
+        status,
+        retry_after,
+        body: body.trim().to_string(),
+    }
+    .into())
+}
+
+/// Parse `Retry-After` given either as seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    Some((at - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Fail with `FileUnstable` if the file's size or mtime no longer match the scan.
+pub async fn ensure_unchanged(file: &ChangedFile) -> Result<()> {
+    if stat(&file.path).await? != (file.entry.size, SystemTime::from(file.entry.mtime)) {
+        return Err(FileUnstable { path: file.path.clone() }.into());
+    }
+    Ok(())
+}
+
+async fn stat(path: &Path) -> Result<(u64, SystemTime)> {
+    let meta = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Reading metadata for {}", path.display()))?;
+    let mtime = meta
+        .modified()
+        .with_context(|| format!("Reading mtime for {}", path.display()))?;
+    Ok((meta.len(), mtime))
+}
@@ -0,0 +1,397 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::cli::{HiddenPolicy, LinkPolicy};
+use crate::scanner::{relative_path, ScanOptions};
+use crate::state::CachedDir;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A regular file found by the walk that passed the filters.
+pub struct WalkedFile {
+    pub path: PathBuf,
+    pub rel_path: String,
+    pub meta: Metadata,
+}
+
+pub struct WalkOutput {
+    /// Files sorted by path.
+    pub files: Vec<WalkedFile>,
+    /// Relative paths of files in directories whose cached listing was reused.
+    /// They were not stated and are assumed unchanged.
+    pub cached_files: Vec<String>,
+    /// Listings of every directory visited, when recording was requested.
+    pub dirs: BTreeMap<String, CachedDir>,
+    /// Directories or entries that could not be read.
+    pub errors: usize,
+}
+
+/// How the walk uses the directory cache.
+pub enum DirListings<'a> {
+    /// List every directory and record nothing.
+    Ignore,
+    /// List every directory and record the listings.
+    Record,
+    /// Reuse cached listings of directories whose mtime is unchanged, recording all listings.
+    Reuse(&'a BTreeMap<String, CachedDir>),
+}
+
+/// A directory waiting to be listed.
+struct PendingDir {
+    path: PathBuf,
+    /// Canonical scan root plus the targets of links followed to reach this directory.
+    /// A link whose target is inside one of these was already walked; one whose target
+    /// contains one of these would loop.
+    chain: Arc<Vec<PathBuf>>,
+}
+
+/// Directories waiting to be listed, and how many are being listed right now.
+struct Queue {
+    pending: Vec<PendingDir>,
+    active: usize,
+}
+
+struct Shared<'a> {
+    root: &'a Path,
+    options: &'a ScanOptions,
+    listings: DirListings<'a>,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    files: Mutex<Vec<WalkedFile>>,
+    cached_files: Mutex<Vec<String>>,
+    dirs: Mutex<BTreeMap<String, CachedDir>>,
+    dirs_visited: AtomicUsize,
+    dirs_reused: AtomicUsize,
+    files_visited: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+/// Walk `root` with `options.workers` threads listing directories and stating files
+/// concurrently. Each listing and stat is a round trip over SMB, so this is where
+/// large shares spend their time.
+pub fn walk(root: &Path, options: &ScanOptions, listings: DirListings) -> WalkOutput {
+    let shared = Shared {
+        root,
+        options,
+        listings,
+        queue: Mutex::new(Queue {
+            pending: vec![PendingDir {
+                path: root.to_path_buf(),
+                chain: Arc::new(vec![std::fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf())]),
+            }],
+            active: 0,
+        }),
+        ready: Condvar::new(),
+        files: Mutex::new(Vec::new()),
+        cached_files: Mutex::new(Vec::new()),
+        dirs: Mutex::new(BTreeMap::new()),
+        dirs_visited: AtomicUsize::new(0),
+        dirs_reused: AtomicUsize::new(0),
+        files_visited: AtomicUsize::new(0),
+        errors: AtomicUsize::new(0),
+    };
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+
+    thread::scope(|s| {
+        let shared = &shared;
+        s.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(PROGRESS_INTERVAL) {
+                info!(
+                    "Scanning: {} directories, {} files visited so far",
+                    shared.dirs_visited.load(Ordering::Relaxed),
+                    shared.files_visited.load(Ordering::Relaxed),
+                );
+            }
+        });
+
+        let workers: Vec<_> = (0..options.workers.max(1))
+            .map(|_| s.spawn(move || worker(shared)))
+            .collect();
+        for w in workers {
+            let _ = w.join();
+        }
+        drop(done_tx);
+    });
+
+    info!(
+        "Scanned {} directories ({} unchanged since last scan), {} files",
+        shared.dirs_visited.load(Ordering::Relaxed),
+        shared.dirs_reused.load(Ordering::Relaxed),
+        shared.files_visited.load(Ordering::Relaxed),
+    );
+
+    let mut files = shared.files.into_inner().unwrap_or_else(|e| e.into_inner());
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut cached_files = shared.cached_files.into_inner().unwrap_or_else(|e| e.into_inner());
+    cached_files.sort();
+    WalkOutput {
+        files,
+        cached_files,
+        dirs: shared.dirs.into_inner().unwrap_or_else(|e| e.into_inner()),
+        errors: shared.errors.load(Ordering::Relaxed),
+    }
+}
+
+fn worker(shared: &Shared) {
+    while let Some(dir) = next_dir(shared) {
+        let subdirs = list_dir(shared, &dir);
+        let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+        queue.pending.extend(subdirs);
+        queue.active -= 1;
+        shared.ready.notify_all();
+    }
+}
+
+/// Take the next directory to list, waiting while other workers may still add more.
+/// Returns `None` once the queue is empty and no directory is being listed, or the scan
+/// was cancelled.
+fn next_dir(shared: &Shared) -> Option<PendingDir> {
+    let mut queue = shared.queue.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+        if shared.options.cancelled.load(Ordering::Relaxed) {
+            shared.ready.notify_all();
+            return None;
+        }
+        if let Some(dir) = queue.pending.pop() {
+            queue.active += 1;
+            return Some(dir);
+        }
+        if queue.active == 0 {
+            return None;
+        }
+        queue = shared.ready.wait(queue).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+/// List one directory, collecting its files and returning the subdirectories to descend into.
+fn list_dir(shared: &Shared, pending: &PendingDir) -> Vec<PendingDir> {
+    let dir = pending.path.as_path();
+    shared.dirs_visited.fetch_add(1, Ordering::Relaxed);
+    let mut subdirs = Vec::new();
+    let rel_dir = relative_path(shared.root, dir);
+
+    // Read the mtime before listing, so entries added during the listing bump it for next time
+    let dir_mtime: Option<DateTime<Utc>> = match shared.listings {
+        DirListings::Ignore => None,
+        _ => std::fs::metadata(dir).and_then(|m| m.modified()).ok().map(Into::into),
+    };
+
+    if let (DirListings::Reuse(cache), Some(mtime)) = (&shared.listings, dir_mtime) {
+        if let Some(cached) = cache.get(&rel_dir).filter(|c| c.mtime == mtime) {
+            shared.dirs_reused.fetch_add(1, Ordering::Relaxed);
+            shared.files_visited.fetch_add(cached.files.len(), Ordering::Relaxed);
+            shared
+                .cached_files
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .extend(cached.files.iter().map(|name| join_rel(&rel_dir, name)));
+            for name in &cached.subdirs {
+                let path = dir.join(name);
+                // Links are not marked in the cache, so check again to keep the chain complete
+                let is_link = shared.options.links == LinkPolicy::Follow
+                    && std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+                let chain = if is_link {
+                    match linked_dir_chain(&pending.chain, &path) {
+                        Some(chain) => chain,
+                        None => continue,
+                    }
+                } else {
+                    pending.chain.clone()
+                };
+                subdirs.push(PendingDir { path, chain });
+            }
+            shared
+                .dirs
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(rel_dir, cached.clone());
+            return subdirs;
+        }
+    }
+
+    let mut listing = CachedDir {
+        mtime: dir_mtime.unwrap_or_default(),
+        subdirs: Vec::new(),
+        files: Vec::new(),
+    };
+    // Partial listings are not cached, so the directory is listed again next time
+    let mut complete = true;
+
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Skipping unreadable directory {}: {}", dir.display(), e);
+            shared.errors.fetch_add(1, Ordering::Relaxed);
+            return subdirs;
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Skipping unreadable entry in {}: {}", dir.display(), e);
+                shared.errors.fetch_add(1, Ordering::Relaxed);
+                complete = false;
+                continue;
+            }
+        };
+        let mut file_type = match entry.file_type() {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Cannot read file type of {}: {}", entry.path().display(), e);
+                shared.errors.fetch_add(1, Ordering::Relaxed);
+                complete = false;
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        let rel_path = relative_path(shared.root, &path);
+
+        // Metadata of the link target when following a link
+        let mut target_meta: Option<Metadata> = None;
+        let mut chain = pending.chain.clone();
+        if file_type.is_symlink() {
+            if shared.options.links == LinkPolicy::Skip {
+                debug!("Skipping link: {}", path.display());
+                continue;
+            }
+            match std::fs::metadata(&path) {
+                Ok(m) => {
+                    file_type = m.file_type();
+                    target_meta = Some(m);
+                }
+                Err(e) => {
+                    warn!("Skipping broken link {}: {}", path.display(), e);
+                    continue;
+                }
+            }
+            if file_type.is_dir() {
+                match linked_dir_chain(&pending.chain, &path) {
+                    Some(c) => chain = c,
+                    None => continue,
+                }
+            }
+        }
+
+        if shared.options.hidden == HiddenPolicy::Ignore {
+            let name = entry.file_name();
+            let hidden = match &target_meta {
+                Some(m) => is_hidden(&name, m),
+                None => entry.metadata().is_ok_and(|m| is_hidden(&name, &m)),
+            };
+            if hidden {
+                debug!("Skipping hidden entry: {}", path.display());
+                continue;
+            }
+        }
+
+        if file_type.is_dir() {
+            let depth = rel_path.split('/').count();
+            if shared.options.filter.is_excluded(&rel_path) {
+                debug!("Skipping excluded directory: {}", path.display());
+            } else if let Some(max) = shared.options.max_depth.filter(|max| depth >= *max) {
+                info!("Skipping directory {}: depth {} reaches --max-depth {}", path.display(), depth, max);
+            } else {
+                listing.subdirs.push(entry.file_name().to_string_lossy().into_owned());
+                subdirs.push(PendingDir { path, chain });
+            }
+            continue;
+        }
+        if !file_type.is_file() {
+            continue;
+        }
+
+        shared.files_visited.fetch_add(1, Ordering::Relaxed);
+        if !shared.options.filter.matches_file(&rel_path) {
+            debug!("Skipping filtered file: {}", path.display());
+            continue;
+        }
+
+        match target_meta.map_or_else(|| entry.metadata(), Ok) {
+            Ok(meta) => {
+                listing.files.push(entry.file_name().to_string_lossy().into_owned());
+                shared
+                    .files
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(WalkedFile { path, rel_path, meta });
+            }
+            Err(e) => {
+                warn!("Cannot read metadata for {}: {}", path.display(), e);
+                shared.errors.fetch_add(1, Ordering::Relaxed);
+                complete = false;
+            }
+        }
+    }
+
+    if dir_mtime.is_some() && complete {
+        shared
+            .dirs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(rel_dir, listing);
+    }
+    subdirs
+}
+
+/// Chain for a directory reached through a link, or `None` if it should not be descended
+/// into because its target was already walked or is an ancestor (a loop).
+fn linked_dir_chain(chain: &Arc<Vec<PathBuf>>, link: &Path) -> Option<Arc<Vec<PathBuf>>> {
+    let target = match std::fs::canonicalize(link) {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Skipping link {}: cannot resolve target: {}", link.display(), e);
+            return None;
+        }
+    };
+    for walked in chain.iter() {
+        if walked.starts_with(&target) {
+            warn!("Skipping link {}: points to its own ancestor {}", link.display(), target.display());
+            return None;
+        }
+        if target.starts_with(walked) {
+            info!("Skipping link {}: {} is already walked", link.display(), target.display());
+            return None;
+        }
+    }
+    let mut extended = chain.as_ref().clone();
+    extended.push(target);
+    Some(Arc::new(extended))
+}
+
+/// Dot-files, and on Windows files with the hidden or system attribute.
+pub fn is_hidden(name: &OsStr, meta: &Metadata) -> bool {
+    name.to_string_lossy().starts_with('.') || has_hidden_attribute(meta)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(meta: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    meta.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_meta: &Metadata) -> bool {
+    false
+}
+
+fn join_rel(rel_dir: &str, name: &str) -> String {
+    if rel_dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", rel_dir, name)
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, info, warn};
+
+/// Decides when the next scan should run in `--watch` mode.
+///
+/// With filesystem notifications, a scan is triggered once events have been quiet for the
+/// debounce period, so a file being written produces a single scan. A periodic poll runs
+/// regardless, which is the only trigger for SMB paths where notifications are unreliable.
+pub struct ChangeWatcher {
+    _watcher: Option<RecommendedWatcher>,
+    rx: mpsc::UnboundedReceiver<()>,
+    debounce: Duration,
+    poll_interval: Duration,
+}
+
+impl ChangeWatcher {
+    /// Start watching `root`. `ignored` paths (state files) never trigger a scan.
+    pub fn new(
+        root: &Path,
+        use_notify: bool,
+        ignored: Vec<PathBuf>,
+        debounce: Duration,
+        poll_interval: Duration,
+    ) -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let watcher = if use_notify {
+            match start_watcher(root, ignored, tx) {
+                Ok(w) => {
+                    info!(
+                        "Watching {} for changes (debounce {}s, full scan every {}s)",
+                        root.display(),
+                        debounce.as_secs(),
+                        poll_interval.as_secs()
+                    );
+                    Some(w)
+                }
+                Err(e) => {
+                    warn!("Filesystem notifications unavailable, falling back to polling: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if watcher.is_none() {
+            info!("Polling {} every {}s", root.display(), poll_interval.as_secs());
+        }
+
+        Ok(ChangeWatcher {
+            _watcher: watcher,
+            rx,
+            debounce,
+            poll_interval,
+        })
+    }
+
+    /// Wait until the next scan should run. `recheck` shortens the wait, e.g. to pick up
+    /// files deferred while still being written. Returns `false` when the process should
+    /// stop because `interrupted` (the session's Ctrl+C listener) completed.
+    pub async fn wait<F>(&mut self, recheck: Option<Duration>, mut interrupted: Pin<&mut F>) -> bool
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        let delay = recheck.map_or(self.poll_interval, |d| d.min(self.poll_interval));
+        tokio::select! {
+            _ = interrupted.as_mut() => {
+                info!("Interrupted, stopping watch mode");
+                return false;
+            }
+            _ = sleep(delay) => {
+                debug!("Poll interval elapsed");
+                return true;
+            }
+            Some(()) = self.rx.recv() => {}
+        }
+
+        // Wait for events to settle before scanning
+        loop {
+            tokio::select! {
+                _ = interrupted.as_mut() => {
+                    info!("Interrupted, stopping watch mode");
+                    return false;
+                }
+                event = timeout(self.debounce, self.rx.recv()) => {
+                    if !matches!(event, Ok(Some(()))) {
+                        break;
+                    }
+                }
+            }
+        }
+        debug!("Filesystem changes settled");
+        true
+    }
+}
+
+fn start_watcher(
+    root: &Path,
+    ignored: Vec<PathBuf>,
+    tx: mpsc::UnboundedSender<()>,
+) -> Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            let relevant = matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) && event
+                .paths
+                .iter()
+                .any(|p| !ignored.iter().any(|i| is_ignored(p, i)));
+            if relevant {
+                debug!("Filesystem event: {:?} {:?}", event.kind, event.paths);
+                let _ = tx.send(());
+            }
+        }
+        Err(e) => warn!("Watch error: {}", e),
+    })
+    .context("Creating filesystem watcher")?;
+
+    watcher
+        .watch(root, RecursiveMode::Recursive)
+        .with_context(|| format!("Watching {}", root.display()))?;
+    Ok(watcher)
+}
+
+/// Matches the ignored path itself and siblings sharing its name as a prefix (e.g. `.tmp` files).
+fn is_ignored(path: &Path, ignored: &Path) -> bool {
+    path.to_string_lossy().starts_with(&*ignored.to_string_lossy())
+}